@@ -90,7 +90,21 @@ pub mod unix {
 pub mod windows {
     //! Windows only extensions.
 
-    pub use crate::sys::named_pipe::NamedPipe;
+    pub use crate::sys::named_pipe::{
+        anonymous_pipe, dispatch_completion, wait_any, BufferAlloc, BufferPool, ConnectionGuard,
+        FrameLength, FramedPipe, HandleState, InstanceStats, Messages, NamedPipe, NamedPipeBuilder,
+        OperationState, OverlappedEvents, OwnedReadHalf, OwnedWriteHalf, PipeAccess,
+        PipeDebugState, PipeError, PipeEvent, PipeInfo, Readiness, ReconnectingClient,
+        WeakNamedPipe, WriteFullPolicy,
+    };
+
+    #[cfg(feature = "test-util")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+    pub use crate::sys::named_pipe::pair;
+
+    #[cfg(feature = "future")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "future")))]
+    pub use crate::sys::named_pipe::Connected;
 }
 
 pub mod features {
@@ -117,6 +131,18 @@ pub mod features {
     #![cfg_attr(not(feature = "net"), doc = "## Network types (disabled)")]
     //!
     //! The `net` feature enables networking primitives in the `net` module.
+    //!
+    #![cfg_attr(feature = "test-util", doc = "## `test-util` (enabled)")]
+    #![cfg_attr(not(feature = "test-util"), doc = "## `test-util` (disabled)")]
+    //!
+    //! `test-util` enables helpers for testing code built on Mio, such as
+    //! `windows::pair`. It implies `os-ext`.
+    //!
+    #![cfg_attr(feature = "future", doc = "## `future` (enabled)")]
+    #![cfg_attr(not(feature = "future"), doc = "## `future` (disabled)")]
+    //!
+    //! `future` enables `Future`s for some OS specific operations, such as
+    //! `windows::NamedPipe::connected`. It implies `os-ext`.
 }
 
 pub mod guide {
@@ -1,21 +1,56 @@
 use crate::event::Source;
-use crate::sys::windows::{Event, Overlapped};
+use crate::poll;
+use crate::sys::windows::{event, Event, Overlapped, Ready, SelectorInner};
 use crate::Registry;
 
-use std::ffi::OsStr;
+use std::cmp;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::io::{self, Read, Write};
+use std::fs::OpenOptions;
+use std::io::{self, IoSlice, Read, Write};
 use std::mem;
+use std::ops::{self, Deref};
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
 use std::os::windows::io::{AsRawHandle, FromRawHandle, IntoRawHandle, RawHandle};
+use std::pin::Pin;
+use std::process;
+use std::ptr;
 use std::slice;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::SeqCst;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
-use crate::{Interest, Token};
+use crate::{Interest, Token, Waker};
+use miow::iocp::CompletionStatus;
 use miow::pipe;
-use winapi::shared::winerror::{ERROR_BROKEN_PIPE, ERROR_PIPE_LISTENING};
-use winapi::um::ioapiset::CancelIoEx;
+use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
+use winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED, ERROR_BROKEN_PIPE, ERROR_FILE_NOT_FOUND, ERROR_IO_PENDING,
+    ERROR_MORE_DATA, ERROR_NOT_FOUND, ERROR_NO_DATA, ERROR_OPERATION_ABORTED, ERROR_PIPE_BUSY,
+    ERROR_PIPE_LISTENING, WAIT_TIMEOUT,
+};
+use winapi::um::fileapi::{ReadFileEx, WriteFileEx};
+use winapi::um::handleapi::{CloseHandle, SetHandleInformation, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::{CancelIoEx, CreateIoCompletionPort, GetOverlappedResult};
+use winapi::um::minwinbase::{OVERLAPPED, OVERLAPPED_ENTRY, SECURITY_ATTRIBUTES};
+use winapi::um::namedpipeapi::{
+    GetNamedPipeInfo, PeekNamedPipe, SetNamedPipeHandleState, TransactNamedPipe, WaitNamedPipeW,
+};
+use winapi::um::processthreadsapi::GetCurrentThread;
+use winapi::um::synchapi::{CreateEventW, SleepEx, WaitForMultipleObjects, WaitForSingleObject};
+use winapi::um::winbase::{
+    CreateNamedPipeW, GetNamedPipeHandleStateW, SetThreadAffinityMask,
+    FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, FILE_FLAG_WRITE_THROUGH,
+    HANDLE_FLAG_INHERIT, INFINITE, PIPE_ACCESS_DUPLEX, PIPE_ACCESS_INBOUND, PIPE_ACCESS_OUTBOUND,
+    PIPE_NOWAIT, PIPE_READMODE_MESSAGE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_SERVER_END,
+    PIPE_TYPE_BYTE, PIPE_TYPE_MESSAGE, PIPE_UNLIMITED_INSTANCES, WAIT_FAILED, WAIT_IO_COMPLETION,
+    WAIT_OBJECT_0,
+};
+use winapi::um::winnt::{HANDLE, MAXIMUM_WAIT_OBJECTS};
 
 /// Non-blocking windows named pipe.
 ///
@@ -38,9 +73,48 @@ use winapi::um::ioapiset::CancelIoEx;
 /// pipe needs to be connected to a client before it can be read or written,
 /// however.
 ///
+/// They can't be implemented for `Arc<NamedPipe>` or `&Arc<NamedPipe>`, the
+/// coherence rules don't allow implementing the standard library's traits
+/// for its types. A pipe shared through an `Arc` is read and written through
+/// the `&NamedPipe` implementations instead, by dereferencing it explicitly,
+/// e.g. `(&*pipe).read(&mut buf)`, or by passing `&*pipe` to code generic
+/// over `Read` or `Write`.
+///
+/// A successful `write` means the data was *accepted into the internal
+/// buffer*, not that it was written to the system. If the system completes the
+/// write immediately the number of bytes it accepted is returned, which may be
+/// less than the whole slice. Otherwise the write stays in flight and the whole
+/// slice is reported as written, further writes return a "would block" error
+/// until it completed. Errors of a write in flight are returned by the next
+/// `write`. Use [`NamedPipe::try_write`] to only write what the system accepts
+/// immediately, and [`NamedPipe::writable_after`] to see how much is still in
+/// flight.
+///
+/// Multiple threads may read from the same pipe concurrently, e.g. one using
+/// the owned value and another using a shared reference. Every `read` copies
+/// out of the internal buffer while holding an internal lock, so concurrent
+/// readers receive disjoint byte ranges and no byte is returned twice. Which
+/// reader receives which range is unspecified however, so concurrent reads
+/// are rarely useful for anything but message mode pipes.
+///
+/// `NamedPipe` intentionally doesn't implement `Clone`, nor is there a
+/// `try_clone` based on `DuplicateHandle`. A duplicated handle refers to the
+/// same pipe instance, so two values with independent internal read buffers
+/// would each read ahead and split the incoming data between them at
+/// arbitrary points. To use a pipe from several places share it instead, e.g.
+/// through an `Arc<NamedPipe>` and the `&NamedPipe` implementations, or split
+/// it using [`NamedPipe::into_split`].
+///
+/// Neither is `Seek` implemented, and it won't be: a named pipe is a stream
+/// without a position, the system fails any attempt to seek it. Generic code
+/// requiring `Seek` can't use a named pipe, rather than getting errors at
+/// runtime.
+///
 /// Note that for I/O operations on a named pipe to succeed then the named pipe
-/// needs to be associated with an event loop. Until this happens all I/O
-/// operations will return a "would block" error.
+/// needs to be associated with an event loop. Register the pipe before calling
+/// `connect`, `read` or `write`. Until this happens (and after a deregister)
+/// they return an error of kind `NotConnected` rather than a "would block"
+/// error, which would be indistinguishable from regular backpressure.
 ///
 /// # Managing connections
 ///
@@ -51,6 +125,24 @@ use winapi::um::ioapiset::CancelIoEx;
 /// The `connect` method will succeed asynchronously and a completion can be
 /// detected once the object receives a writable notification.
 ///
+/// # Writable notifications
+///
+/// A writable notification is only issued when the pipe transitions to being
+/// writable, i.e. after registering, once a write completes or once a connect
+/// finishes. Reregistering, e.g. to change the token, doesn't issue another
+/// one.
+///
+/// # CPU affinity
+///
+/// Completions are processed by the thread calling `Poll::poll`, so binding
+/// the completions of a group of pipes to a core means registering the group
+/// with a `Poll` of its own and polling it from a thread pinned to that core,
+/// e.g. using `SetThreadAffinityMask`. Completion port loops sharing the port
+/// can tell the pipes apart by [`NamedPipe::completion_key`], or be signalled
+/// through [`NamedPipe::set_overlapped_events`]. The only thread owned by a
+/// pipe, the one enforcing timeouts, can be pinned using
+/// [`NamedPipeBuilder::timer_affinity`].
+///
 /// # Named pipe clients
 ///
 /// Currently to create a client of a named pipe server then you can use the
@@ -69,16 +161,66 @@ pub struct NamedPipe {
 /// `ptr_from_*_overlapped` methods depend on it, see the `ptr_from` test.
 #[repr(C)]
 struct Inner {
-    // NOTE: careful modifying the order of these three fields, the `ptr_from_*`
+    // NOTE: careful modifying the order of these four fields, the `ptr_from_*`
     // methods depend on the layout!
     connect: Overlapped,
     read: Overlapped,
     write: Overlapped,
+    // Posted to report events outside of `Poll::poll`, see `Inner::post`.
+    event: Overlapped,
     // END NOTE.
     handle: pipe::NamedPipe,
     connecting: AtomicBool,
     io: Mutex<Io>,
-    pool: Mutex<BufferPool>,
+    // Source of the internal buffers, possibly shared with other pipes.
+    alloc: Arc<dyn BufferAlloc>,
+    // Direction(s) in which the pipe can be used.
+    access: PipeAccess,
+    // Bypass the internal buffers, see `NamedPipeBuilder::direct_mode`.
+    direct: bool,
+    // Upper bound of the bytes requested by an internal read.
+    max_read_ahead: usize,
+    // What `write` does while a write is in flight.
+    write_full_policy: WriteFullPolicy,
+    // Signalled by `write_done`, see `WriteFullPolicy::Block`.
+    write_cond: Condvar,
+    // Signalled by `read_done`, see `NamedPipe::read_timeout_once`.
+    read_cond: Condvar,
+    // Signalled by `connect_done`, see `NamedPipe::accept_and_read`.
+    connect_cond: Condvar,
+    // Address the pipe was created at, unknown for raw handles.
+    addr: Option<OsString>,
+    // Event handles set on `connect`, `read` and `write`, see
+    // `NamedPipe::set_overlapped_events`.
+    events: Mutex<OverlappedEvents>,
+    // See `NamedPipeBuilder::completion_key`.
+    completion_key: Option<usize>,
+    // See `NamedPipeBuilder::max_message_size`.
+    max_message_size: Option<usize>,
+    // See `NamedPipeBuilder::strict_eof`.
+    strict_eof: bool,
+    // Woken once a connect completes, see `NamedPipe::connected`.
+    connect_waker: Mutex<Option<task::Waker>>,
+    // See `NamedPipeBuilder::sub_tokens`.
+    sub_tokens: bool,
+    // See `NamedPipeBuilder::connect_retries`.
+    connect_retries: u32,
+    // See `NamedPipeBuilder::timer_affinity`.
+    timer_affinity: Option<usize>,
+    // See `NamedPipeBuilder::buffer_alignment`.
+    buffer_alignment: usize,
+    // Largest size the read buffers grow to, see
+    // `NamedPipeBuilder::adaptive_read_size`.
+    max_read_size: Option<usize>,
+    // See `NamedPipeBuilder::max_events_per_poll`.
+    max_events_per_poll: Option<usize>,
+    // See `NamedPipe::bytes_read` and `NamedPipe::bytes_written`.
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    // Number of `NamedPipe` values and split halves sharing this state, see
+    // `NamedPipe::downgrade`. Unlike the strong count of the `Arc` this
+    // doesn't include the references held by operations in flight.
+    owners: AtomicUsize,
 }
 
 #[test]
@@ -103,36 +245,607 @@ fn ptr_from() {
         unsafe { Inner::ptr_from_write_overlapped(&inner.write as *const _ as *mut OVERLAPPED) },
         "`ptr_from_write_overlapped` incorrect"
     );
+    assert_eq!(
+        inner as *const Inner,
+        unsafe { Inner::ptr_from_event_overlapped(&inner.event as *const _ as *mut OVERLAPPED) },
+        "`ptr_from_event_overlapped` incorrect"
+    );
 }
 
 struct Io {
     // Token used to identify events
     token: Option<Token>,
+    // Selector the handle is associated with, set by the first `register`.
+    selector: Option<Arc<SelectorInner>>,
+    // Operations whose readiness is posted but not reported yet, see
+    // `Inner::post`.
+    posted: u8,
     read: State,
     write: State,
     connect_error: Option<io::Error>,
+    // Whether a connect completed successfully since the last `disconnect`,
+    // see `NamedPipe::poll_connected`.
+    connected: bool,
+    // Whether a writable notification was issued for the current write state,
+    // cleared when a write is issued or a connect finishes. See
+    // `notify_writable_edge`.
+    writable_notified: bool,
+    // Timeouts set by `set_read_timeout` and `set_write_timeout`.
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // Deadlines of the pending read and write, if a timeout is set.
+    read_deadline: Option<Instant>,
+    write_deadline: Option<Instant>,
+    // Whether the pending read or write was cancelled by the timer thread.
+    read_timed_out: bool,
+    write_timed_out: bool,
+    // Same as above for the pending connect, see
+    // `NamedPipe::connect_with_deadline`.
+    connect_deadline: Option<Instant>,
+    connect_timed_out: bool,
+    // When the timer thread retries a connect which failed with a transient
+    // error, and the number of the attempt, see
+    // `NamedPipeBuilder::connect_retries`.
+    connect_retry: Option<(Instant, u32)>,
+    // Thread enforcing the deadlines above, see `run_timer`.
+    timer: Option<Thread>,
+    // Error that ended the stream when `read` last returned `Ok(0)`.
+    eof_error: Option<io::Error>,
+    // Whether the last read completed in the middle of a message, i.e. with
+    // `ERROR_MORE_DATA`.
+    mid_message: bool,
+    // Whether the stream ended in the middle of a message.
+    eof_truncated: bool,
+    // See `NamedPipe::set_level_triggered`.
+    level_triggered: bool,
+    // Whether the completion of the pending read or write belongs to a
+    // previous client and must be discarded, see `NamedPipe::recycle`.
+    discard_read: bool,
+    discard_write: bool,
+    // Whether the pending write was cancelled by `NamedPipe::try_write`.
+    retract_write: bool,
+    // Bytes transferred by the last processed read and write completion.
+    last_read_len: usize,
+    last_write_len: usize,
+    // See `NamedPipe::last_write_was_synchronous`.
+    last_write_sync: bool,
+    // Interest of the last `register` or `reregister`, reads are only
+    // scheduled while it includes readable.
+    interest: Option<Interest>,
+    // Whether the pending read was cancelled as the readable interest was
+    // removed or the pipe was deregistered, see `NamedPipe::reregister`.
+    read_cancelled: bool,
+    // Whether the pending read was cancelled by `NamedPipe::refresh_read`.
+    read_refreshed: bool,
+    // Number of reads and writes that found an operation still in flight.
+    would_block_count: u64,
+    // See `NamedPipe::set_blocking`.
+    blocking: bool,
+    // Whether reads are only scheduled after `NamedPipe::start_reading`.
+    reading_deferred: bool,
+    // See `NamedPipe::set_apc_mode`.
+    apc: bool,
+    // See `NamedPipe::associate_with_iocp`.
+    foreign_port: bool,
+    // See `NamedPipe::last_os_error_code`.
+    last_os_error: Option<i32>,
+    // Size of the buffers of the next reads, see
+    // `NamedPipeBuilder::adaptive_read_size`.
+    read_size: usize,
+    // See `NamedPipe::set_lifecycle_hook`.
+    lifecycle_hook: Option<Arc<LifecycleHook>>,
 }
 
+type LifecycleHook = dyn Fn(PipeEvent) + Send + Sync;
+
 #[derive(Debug)]
 enum State {
     None,
     Pending(Vec<u8>, usize),
     Ok(Vec<u8>, usize),
     Err(io::Error),
+    /// A read issued by `NamedPipe::read_overlapped_into` is in flight, the
+    /// kernel is writing into the caller's buffer.
+    DirectPending,
+    /// A read issued by `NamedPipe::read_overlapped_into` completed with the
+    /// given number of bytes.
+    DirectOk(usize),
+}
+
+/// Direction(s) in which a [`NamedPipe`] server can be used, see
+/// [`NamedPipeBuilder::access`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeAccess {
+    /// Data only flows from the client to the server, `PIPE_ACCESS_INBOUND`.
+    Inbound,
+    /// Data only flows from the server to the client, `PIPE_ACCESS_OUTBOUND`.
+    Outbound,
+    /// Data flows in both directions, `PIPE_ACCESS_DUPLEX`.
+    Duplex,
+}
+
+impl PipeAccess {
+    fn open_mode(self) -> DWORD {
+        match self {
+            PipeAccess::Inbound => PIPE_ACCESS_INBOUND,
+            PipeAccess::Outbound => PIPE_ACCESS_OUTBOUND,
+            PipeAccess::Duplex => PIPE_ACCESS_DUPLEX,
+        }
+    }
+}
+
+/// Information about a named pipe, returned by [`NamedPipe::info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipeInfo {
+    /// Whether this is the server end of the pipe.
+    pub server_end: bool,
+    /// Whether the pipe was created in message mode, rather than byte mode.
+    pub message_mode: bool,
+    /// Size of the buffer for outgoing data, in bytes.
+    pub out_buffer_size: u32,
+    /// Size of the buffer for incoming data, in bytes.
+    pub in_buffer_size: u32,
+    /// Maximum number of instances of the pipe, `None` if unlimited.
+    pub max_instances: Option<u32>,
+}
+
+/// State of a named pipe handle, returned by [`NamedPipe::handle_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandleState {
+    /// Whether data is read as messages, rather than as a stream of bytes.
+    pub message_read_mode: bool,
+    /// Whether the handle is in nonblocking (`PIPE_NOWAIT`) mode.
+    pub nowait: bool,
+    /// Number of instances of the pipe that currently exist.
+    pub current_instances: u32,
+}
+
+/// Instance counts of a named pipe, returned by [`NamedPipe::instance_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstanceStats {
+    /// Number of instances that currently exist.
+    pub current: u32,
+    /// Maximum number of instances, `None` if unlimited.
+    pub max: Option<u32>,
+}
+
+impl InstanceStats {
+    /// Returns `true` if no more instances can be created.
+    pub fn is_full(&self) -> bool {
+        self.max.map_or(false, |max| self.current >= max)
+    }
+}
+
+/// A snapshot of the internal state of a [`NamedPipe`], returned by
+/// [`NamedPipe::debug_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipeDebugState {
+    /// State of the internal read operation.
+    pub read: OperationState,
+    /// State of the internal write operation.
+    pub write: OperationState,
+    /// Whether a `connect` is in progress.
+    pub connecting: bool,
+    /// Whether an error of a `connect` is waiting to be taken by
+    /// `take_error`.
+    pub connect_error: bool,
+    /// Whether the last end-of-file returned by `read` happened in the middle
+    /// of a message, i.e. the peer left before the message was completely
+    /// written. Only message mode pipes can detect this.
+    pub eof_truncated: bool,
+}
+
+/// State of an overlapped operation of a [`NamedPipe`], see
+/// [`PipeDebugState`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationState {
+    /// No operation is in flight.
+    Idle,
+    /// The operation is in flight.
+    Pending,
+    /// The operation completed, its data has not been consumed yet.
+    Completed,
+    /// The operation failed, its error has not been returned yet.
+    Errored,
+}
+
+/// The operations of a [`NamedPipe`] which are currently ready, returned by
+/// [`NamedPipe::readiness`].
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Readiness(u8);
+
+impl Readiness {
+    /// Reading doesn't return a "would block" error: data, the end of the
+    /// stream or an error is available.
+    pub const READABLE: Readiness = Readiness(0b001);
+    /// Writing doesn't return a "would block" error.
+    pub const WRITABLE: Readiness = Readiness(0b010);
+    /// No connect is in progress, see [`NamedPipe::poll_connected`].
+    pub const CONNECTED: Readiness = Readiness(0b100);
+
+    /// Returns an empty set.
+    pub fn empty() -> Readiness {
+        Readiness(0)
+    }
+
+    /// Returns true if no operation is ready.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns true if all of `other` is ready.
+    pub fn contains(self, other: Readiness) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if the value includes readable readiness.
+    pub fn is_readable(self) -> bool {
+        self.contains(Readiness::READABLE)
+    }
+
+    /// Returns true if the value includes writable readiness.
+    pub fn is_writable(self) -> bool {
+        self.contains(Readiness::WRITABLE)
+    }
+
+    /// Returns true if the value includes connected readiness.
+    pub fn is_connected(self) -> bool {
+        self.contains(Readiness::CONNECTED)
+    }
+}
+
+impl ops::BitOr for Readiness {
+    type Output = Readiness;
+
+    fn bitor(self, other: Readiness) -> Readiness {
+        Readiness(self.0 | other.0)
+    }
+}
+
+impl ops::BitOrAssign for Readiness {
+    fn bitor_assign(&mut self, other: Readiness) {
+        self.0 |= other.0;
+    }
+}
+
+impl fmt::Debug for Readiness {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Readiness::READABLE, "READABLE"),
+            (Readiness::WRITABLE, "WRITABLE"),
+            (Readiness::CONNECTED, "CONNECTED"),
+        ];
+        let mut one = false;
+        for &(readiness, name) in names.iter() {
+            if self.contains(readiness) {
+                if one {
+                    write!(fmt, " | ")?
+                }
+                write!(fmt, "{}", name)?;
+                one = true
+            }
+        }
+        if !one {
+            write!(fmt, "(empty)")?
+        }
+        Ok(())
+    }
+}
+
+/// A connection lifecycle transition of a [`NamedPipe`], see
+/// [`NamedPipe::set_lifecycle_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeEvent {
+    /// A connect completed successfully.
+    Connected,
+    /// The pipe was disconnected using [`NamedPipe::disconnect`].
+    Disconnected,
+    /// A read completed with the end of the stream, i.e. the peer left.
+    Eof,
+}
+
+/// What writing to a [`NamedPipe`] does while a previous write is still in
+/// flight, see [`NamedPipeBuilder::write_full_policy`].
+///
+/// This only applies to the `Write` implementation, methods like
+/// [`NamedPipe::try_write`] always return a "would block" error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteFullPolicy {
+    /// Return a "would block" error, the pipe is flagged as writable once the
+    /// write completed. This is what event loops expect.
+    WouldBlock,
+    /// Return an error of kind `WriteZero`, for applications treating a slow
+    /// peer as a failure rather than backpressure.
+    WriteZero,
+    /// Block the calling thread until the write completed, returning an error
+    /// of kind `TimedOut` if that takes longer than the given duration.
+    ///
+    /// Completions are processed by `Poll`, so the thread polling the pipe
+    /// must not be the one writing to it, otherwise every write blocked like
+    /// this times out.
+    Block(Duration),
+}
+
+/// Classification of the errors specific to named pipes, see
+/// [`PipeError::classify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    /// The server is waiting for a client to connect (`ERROR_PIPE_LISTENING`).
+    Listening,
+    /// The other end of the pipe was closed (`ERROR_BROKEN_PIPE`).
+    BrokenPipe,
+    /// The pipe is being closed (`ERROR_NO_DATA`).
+    NoData,
+    /// All instances of the pipe are busy (`ERROR_PIPE_BUSY`).
+    Busy,
+    /// More data of the current message is available (`ERROR_MORE_DATA`).
+    MoreData,
+    /// Any other error.
+    Other,
+}
+
+impl PipeError {
+    /// Classifies `err` by its OS error code.
+    pub fn classify(err: &io::Error) -> PipeError {
+        match err.raw_os_error().map(|code| code as DWORD) {
+            Some(ERROR_PIPE_LISTENING) => PipeError::Listening,
+            Some(ERROR_BROKEN_PIPE) => PipeError::BrokenPipe,
+            Some(ERROR_NO_DATA) => PipeError::NoData,
+            Some(ERROR_PIPE_BUSY) => PipeError::Busy,
+            Some(ERROR_MORE_DATA) => PipeError::MoreData,
+            _ => PipeError::Other,
+        }
+    }
+}
+
+impl State {
+    fn debug_state(&self) -> OperationState {
+        match self {
+            State::None => OperationState::Idle,
+            State::Pending(..) | State::DirectPending => OperationState::Pending,
+            State::Ok(..) | State::DirectOk(_) => OperationState::Completed,
+            State::Err(_) => OperationState::Errored,
+        }
+    }
+
+    /// Capacity of the buffer held, or of a newly allocated one if none is.
+    fn buffer_capacity(&self) -> usize {
+        match self {
+            State::Pending(buf, _) | State::Ok(buf, _) => buf.capacity(),
+            _ => DEFAULT_BUFFER_SIZE,
+        }
+    }
 }
 
 fn would_block() -> io::Error {
     io::ErrorKind::WouldBlock.into()
 }
 
+fn not_registered() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "named pipe not registered with `Registry`",
+    )
+}
+
+/// Whether a failed connect can be retried, see
+/// `NamedPipeBuilder::connect_retries`.
+fn is_transient(err: &io::Error) -> bool {
+    match err.raw_os_error().map(|code| code as DWORD) {
+        Some(ERROR_NO_DATA) | Some(ERROR_PIPE_LISTENING) => true,
+        _ => false,
+    }
+}
+
+fn blocking_mode() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "named pipe is in blocking or APC mode",
+    )
+}
+
+fn timed_out(op: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("named pipe {} timed out", op),
+    )
+}
+
+/// Wraps an error of the connect in `NamedPipe::accept_and_read`, so it can
+/// be told apart from an error of the read.
+fn connect_failed(err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), err)
+}
+
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::from_secs(0)) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Maximum time the timer thread sleeps while no deadline is pending, so it
+/// notices the pipe was dropped.
+const TIMER_IDLE: Duration = Duration::from_secs(1);
+
+/// Minimum capacity of the buffers used for the internal buffering.
+const DEFAULT_BUFFER_SIZE: usize = 4 * 1024;
+
+/// Default of `FramedPipe::set_max_frame_size`.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
 impl NamedPipe {
     /// Creates a new named pipe at the specified `addr` given a "reasonable
     /// set" of initial configuration options.
+    ///
+    /// Use [`NamedPipeBuilder`] to create a named pipe with other options.
     pub fn new<A: AsRef<OsStr>>(addr: A) -> io::Result<NamedPipe> {
-        let pipe = pipe::NamedPipe::new(addr)?;
-        // Safety: nothing actually unsafe about this. The trait fn includes
-        // `unsafe`.
-        Ok(unsafe { NamedPipe::from_raw_handle(pipe.into_raw_handle()) })
+        NamedPipeBuilder::new(addr).create()
+    }
+
+    /// Creates a new named pipe like [`new`] and registers it with
+    /// `registry`.
+    ///
+    /// Until a pipe is registered all I/O on it fails, this avoids forgetting
+    /// to do so. The pipe is dropped again if registering it fails.
+    ///
+    /// [`new`]: NamedPipe::new
+    pub fn new_registered<A: AsRef<OsStr>>(
+        addr: A,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<NamedPipe> {
+        let mut pipe = NamedPipe::new(addr)?;
+        pipe.register(registry, token, interests)?;
+        Ok(pipe)
+    }
+
+    /// Creates a named pipe from a type owning a handle, e.g. the `File` of a
+    /// client opened with `FILE_FLAG_OVERLAPPED`.
+    ///
+    /// This is the safe counterpart of [`FromRawHandle::from_raw_handle`].
+    pub fn from_handle<T: IntoRawHandle>(handle: T) -> NamedPipe {
+        // Safety: `handle` gives up its ownership of the handle to us.
+        unsafe { NamedPipe::from_raw_handle(handle.into_raw_handle()) }
+    }
+
+    /// Returns the address this named pipe was created at.
+    ///
+    /// Returns `None` if the pipe was created from a handle, e.g. using
+    /// [`from_handle`], as the address is unknown then.
+    ///
+    /// [`from_handle`]: NamedPipe::from_handle
+    pub fn addr(&self) -> Option<&OsStr> {
+        self.inner.addr.as_ref().map(|addr| addr.as_os_str())
+    }
+
+    /// Returns the completion key the handle is associated with the
+    /// completion port of a `Poll` under, once registered.
+    ///
+    /// This is the key set by [`NamedPipeBuilder::completion_key`], or the
+    /// address of the pipe's internal state otherwise, which is unique among
+    /// the live pipes. A pipe associated using [`associate_with_iocp`] uses
+    /// the key passed there instead.
+    ///
+    /// [`associate_with_iocp`]: NamedPipe::associate_with_iocp
+    pub fn completion_key(&self) -> usize {
+        self.inner.completion_key()
+    }
+
+    /// Splits this named pipe into an owned read half and an owned write half.
+    ///
+    /// The halves can be moved to different threads, unlike `&NamedPipe`
+    /// which needs the pipe to outlive both of them. Register the pipe before
+    /// splitting it, the halves can't be (re)registered. Each half counts as
+    /// a named pipe sharing the state, see [`downgrade`]: dropping the last
+    /// of them cancels a pending read and connect but lets a pending write
+    /// complete. Use [`unsplit`] to get the named pipe back.
+    ///
+    /// [`downgrade`]: NamedPipe::downgrade
+    /// [`unsplit`]: NamedPipe::unsplit
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        // The halves take over the ownership of this pipe and add another.
+        self.inner.owners.fetch_add(2, SeqCst);
+        let inner = self.into_inner();
+        (
+            OwnedReadHalf {
+                inner: inner.clone(),
+            },
+            OwnedWriteHalf { inner },
+        )
+    }
+
+    /// Reunites the halves returned by [`into_split`].
+    ///
+    /// Returns the halves back if they didn't come from the same named pipe.
+    ///
+    /// [`into_split`]: NamedPipe::into_split
+    pub fn unsplit(
+        read: OwnedReadHalf,
+        write: OwnedWriteHalf,
+    ) -> Result<NamedPipe, (OwnedReadHalf, OwnedWriteHalf)> {
+        if !Arc::ptr_eq(&read.inner, &write.inner) {
+            return Err((read, write));
+        }
+        // The named pipe takes over the ownership of the write half, skip
+        // the `Drop` implementations of both.
+        read.inner.owners.fetch_sub(1, SeqCst);
+        let read = mem::ManuallyDrop::new(read);
+        let write = mem::ManuallyDrop::new(write);
+        // Safety: neither half is used or dropped afterwards.
+        unsafe {
+            drop(ptr::read(&read.inner));
+            Ok(NamedPipe {
+                inner: ptr::read(&write.inner),
+            })
+        }
+    }
+
+    /// Converts this named pipe into the underlying `miow` named pipe.
+    ///
+    /// This is only possible while no overlapped operation is in flight, as
+    /// those keep referencing the internal state until their completion is
+    /// processed by `Poll`. Note that a registered pipe always has a read in
+    /// flight, so deregister it and let `Poll` process the outstanding
+    /// completions first. Otherwise an error is returned and the named pipe
+    /// is dropped, cancelling the pending read and connect but letting a
+    /// pending write complete.
+    ///
+    /// Data already read into the internal buffer but not yet returned by
+    /// `read` is lost, as is the error of a failed write not yet returned by
+    /// `write`.
+    pub fn into_miow(self) -> io::Result<pipe::NamedPipe> {
+        match Arc::try_unwrap(self.into_inner()) {
+            Ok(inner) => Ok(inner.handle),
+            Err(inner) => {
+                drop(NamedPipe::from_inner(inner));
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "named pipe has overlapped operations in flight",
+                ))
+            }
+        }
+    }
+
+    /// Takes `inner` out of this named pipe without running `Drop`.
+    fn into_inner(self) -> Arc<Inner> {
+        let me = mem::ManuallyDrop::new(self);
+        me.inner.owners.fetch_sub(1, SeqCst);
+        // Safety: `me` is never used or dropped afterwards.
+        unsafe { ptr::read(&me.inner) }
+    }
+
+    /// Reverse of `into_inner`.
+    fn from_inner(inner: Arc<Inner>) -> NamedPipe {
+        inner.owners.fetch_add(1, SeqCst);
+        NamedPipe { inner }
+    }
+
+    /// Creates a weak reference to this named pipe, which doesn't keep it
+    /// open.
+    ///
+    /// This allows e.g. a connection manager to keep track of pipes owned
+    /// elsewhere. [`WeakNamedPipe::upgrade`] returns another `NamedPipe`
+    /// sharing the state of this one, like a `&NamedPipe` does. Dropping it
+    /// doesn't cancel the pending read, only dropping the last of them does.
+    ///
+    /// Operations in flight keep the internal state alive until their
+    /// completion is processed, after the last `NamedPipe` was dropped.
+    /// Upgrading fails from that moment on, not only once the state is gone,
+    /// so a dropped pipe is never revived. The halves returned by
+    /// [`into_split`] count as named pipes here, so upgrading succeeds while
+    /// either of them is alive.
+    ///
+    /// [`into_split`]: NamedPipe::into_split
+    pub fn downgrade(&self) -> WeakNamedPipe {
+        WeakNamedPipe {
+            inner: Arc::downgrade(&self.inner),
+        }
     }
 
     /// Attempts to call `ConnectNamedPipe`, if possible.
@@ -148,49 +861,80 @@ impl NamedPipe {
     ///
     /// # Errors
     ///
-    /// This function will return a "would block" error if the pipe has not yet
-    /// been registered with an event loop, if the connection operation has
+    /// This function will return an error of kind `NotConnected` if the pipe
+    /// has not yet been registered with an event loop.
+    ///
+    /// A "would block" error is returned if the connection operation has
     /// previously been issued but has not yet completed, or if the connect
     /// itself was issued and didn't finish immediately.
     ///
     /// Normal I/O errors from the call to `ConnectNamedPipe` are returned
     /// immediately.
     pub fn connect(&self) -> io::Result<()> {
+        self.connect_inner(None)
+    }
+
+    /// Same as [`connect`], but the connect is cancelled if no client
+    /// connected by `deadline`.
+    ///
+    /// The connect is cancelled by the thread enforcing the timeouts, see
+    /// [`set_read_timeout`]. Its completion is processed by `Poll` like any
+    /// other, so the pipe is flagged as writable and [`take_error`] (or
+    /// [`poll_connected`]) returns an error of kind `TimedOut`. This doesn't
+    /// require shortening the timeout passed to `Poll::poll`: the completion
+    /// wakes up the poll once the deadline passed, whatever its timeout, as
+    /// long as the event loop keeps polling. A connect that completes
+    /// immediately ignores the deadline.
+    ///
+    /// [`connect`]: NamedPipe::connect
+    /// [`set_read_timeout`]: NamedPipe::set_read_timeout
+    /// [`take_error`]: NamedPipe::take_error
+    /// [`poll_connected`]: NamedPipe::poll_connected
+    pub fn connect_with_deadline(&self, deadline: Instant) -> io::Result<()> {
+        self.connect_inner(Some(deadline))
+    }
+
+    fn connect_inner(&self, deadline: Option<Instant>) -> io::Result<()> {
+        // Without registration the completion would never be processed.
+        let io = self.inner.io.lock().unwrap();
+        if io.apc {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "named pipes in APC mode can't connect",
+            ));
+        }
+        if !io.is_active() {
+            return Err(not_registered());
+        }
+        drop(io);
+
         // "Acquire the connecting lock" or otherwise just make sure we're the
         // only operation that's using the `connect` overlapped instance.
         if self.inner.connecting.swap(true, SeqCst) {
             return Err(would_block());
         }
 
-        // Now that we've flagged ourselves in the connecting state, issue the
-        // connection attempt. Afterwards interpret the return value and set
-        // internal state accordingly.
-        let res = unsafe {
-            let overlapped = self.inner.connect.as_ptr() as *mut _;
-            self.inner.handle.connect_overlapped(overlapped)
-        };
-
-        match res {
-            // The connection operation finished immediately, so let's schedule
-            // reads/writes and such.
-            Ok(true) => {
+        // The deadline is in place before the connect is issued, so it can't
+        // outlive the connect's completion.
+        let mut io = self.inner.io.lock().unwrap();
+        io.connect_deadline = deadline;
+        io.connect_timed_out = false;
+        if deadline.is_some() {
+            if let Some(timer) = &io.timer {
+                timer.unpark();
+            } else if let Err(e) = Inner::start_timer(&self.inner, &mut io) {
+                io.connect_deadline = None;
                 self.inner.connecting.store(false, SeqCst);
-                Inner::post_register(&self.inner, None);
-                Ok(())
-            }
-
-            // If the overlapped operation was successful and didn't finish
-            // immediately then we forget a copy of the arc we hold
-            // internally. This ensures that when the completion status comes
-            // in for the I/O operation finishing it'll have a reference
-            // associated with it and our data will still be valid. The
-            // `connect_done` function will "reify" this forgotten pointer to
-            // drop the refcount on the other side.
-            Ok(false) => {
-                mem::forget(self.inner.clone());
-                Err(would_block())
+                return Err(e);
             }
+        }
+        drop(io);
 
+        // Now that we've flagged ourselves in the connecting state, issue the
+        // connection attempt.
+        match Inner::start_connect(&self.inner, 0) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(would_block()),
             Err(e) => {
                 self.inner.connecting.store(false, SeqCst);
                 Err(e)
@@ -211,337 +955,4602 @@ impl NamedPipe {
         Ok(self.inner.io.lock().unwrap().connect_error.take())
     }
 
-    /// Disconnects this named pipe from a connected client.
+    /// Returns whether an error is stored that [`take_error`] would return,
+    /// without removing it.
     ///
-    /// This function will disconnect the pipe from a connected client, if any,
-    /// transitively calling the `DisconnectNamedPipe` function.
+    /// [`take_error`]: NamedPipe::take_error
+    pub fn has_error(&self) -> bool {
+        self.inner.io.lock().unwrap().connect_error.is_some()
+    }
+
+    /// Returns the kind of the error [`take_error`] would return, without
+    /// removing it.
     ///
-    /// After a `disconnect` is issued, then a `connect` may be called again to
-    /// connect to another client.
-    pub fn disconnect(&self) -> io::Result<()> {
-        self.inner.handle.disconnect()
+    /// This allows inspecting the error, e.g. to log it, and only calling
+    /// `take_error` once it's going to be handled.
+    ///
+    /// [`take_error`]: NamedPipe::take_error
+    pub fn error_kind(&self) -> Option<io::ErrorKind> {
+        let io = self.inner.io.lock().unwrap();
+        io.connect_error.as_ref().map(io::Error::kind)
     }
-}
 
-impl FromRawHandle for NamedPipe {
-    unsafe fn from_raw_handle(_handle: RawHandle) -> NamedPipe {
-        unimplemented!()
+    /// Sets a hook called on the connection lifecycle transitions of this
+    /// named pipe, or removes it when passing `None`.
+    ///
+    /// This allows keeping track of connections, e.g. in a registry of
+    /// clients, without polling the pipe's state. The hook is called with
+    /// [`PipeEvent::Connected`] when a connect succeeds, with
+    /// [`PipeEvent::Disconnected`] after `disconnect` and with
+    /// [`PipeEvent::Eof`] when a read completes with the end of the stream.
+    /// If the peer is already gone when a read is issued, the end of the
+    /// stream is only reported by `read`.
+    ///
+    /// Except for `Disconnected` the hook is called while processing the
+    /// completions in `Poll::poll`, or in `connect` if it completes
+    /// immediately. It must not block, as that stalls the event loop. It is
+    /// called without holding any locks of the pipe.
+    pub fn set_lifecycle_hook(&self, hook: Option<Box<dyn Fn(PipeEvent) + Send + Sync>>) {
+        self.inner.io.lock().unwrap().lifecycle_hook = hook.map(Arc::from);
     }
-}
 
-impl Read for NamedPipe {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        <&NamedPipe as Read>::read(&mut &*self, buf)
+    /// Returns the number of instances of this named pipe that currently
+    /// exist.
+    ///
+    /// This wraps `GetNamedPipeHandleStateW`. Servers can use it to decide
+    /// whether to create another instance, before clients start running into
+    /// `ERROR_PIPE_BUSY`.
+    pub fn current_instances(&self) -> io::Result<u32> {
+        self.raw_handle_state().map(|(_, instances)| instances)
     }
-}
 
-impl Write for NamedPipe {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        <&NamedPipe as Write>::write(&mut &*self, buf)
+    /// Returns the current state of this named pipe's handle, wrapping
+    /// `GetNamedPipeHandleStateW`.
+    ///
+    /// This can be used to confirm that e.g. [`set_nowait`] took effect.
+    ///
+    /// [`set_nowait`]: NamedPipe::set_nowait
+    pub fn handle_state(&self) -> io::Result<HandleState> {
+        let (state, current_instances) = self.raw_handle_state()?;
+        Ok(HandleState {
+            message_read_mode: state & PIPE_READMODE_MESSAGE != 0,
+            nowait: state & PIPE_NOWAIT != 0,
+            current_instances,
+        })
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        <&NamedPipe as Write>::flush(&mut &*self)
+    /// Returns information about this named pipe, wrapping
+    /// `GetNamedPipeInfo`.
+    pub fn info(&self) -> io::Result<PipeInfo> {
+        let mut flags = 0;
+        let mut out_buffer_size = 0;
+        let mut in_buffer_size = 0;
+        let mut max_instances = 0;
+        syscall!(
+            GetNamedPipeInfo(
+                self.as_raw_handle() as _,
+                &mut flags,
+                &mut out_buffer_size,
+                &mut in_buffer_size,
+                &mut max_instances,
+            ),
+            PartialEq::eq,
+            FALSE
+        )?;
+        Ok(PipeInfo {
+            server_end: flags & PIPE_SERVER_END != 0,
+            message_mode: flags & PIPE_TYPE_MESSAGE != 0,
+            out_buffer_size,
+            in_buffer_size,
+            max_instances: if max_instances == PIPE_UNLIMITED_INSTANCES {
+                None
+            } else {
+                Some(max_instances)
+            },
+        })
     }
-}
 
-impl<'a> Read for &'a NamedPipe {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut state = self.inner.io.lock().unwrap();
+    /// Returns the maximum number of instances of this named pipe, `None` if
+    /// unlimited.
+    pub fn max_instances(&self) -> io::Result<Option<u32>> {
+        self.info().map(|info| info.max_instances)
+    }
 
-        if state.token.is_none() {
-            return Err(would_block());
-        }
+    /// Returns both the current and maximum number of instances of this named
+    /// pipe.
+    ///
+    /// A supervisor can use this to detect a server running at capacity,
+    /// which should create more instances.
+    pub fn instance_stats(&self) -> io::Result<InstanceStats> {
+        Ok(InstanceStats {
+            current: self.current_instances()?,
+            max: self.max_instances()?,
+        })
+    }
 
-        match mem::replace(&mut state.read, State::None) {
-            // In theory not possible with `token` checked above,
-            // but return would block for now.
-            State::None => Err(would_block()),
+    /// Toggles the `PIPE_NOWAIT` mode of this named pipe.
+    ///
+    /// This wraps `SetNamedPipeHandleState` and is provided *only* for interop
+    /// with processes that depend on it. In non-blocking mode `ReadFile` and
+    /// `WriteFile` fail fast instead of staying pending, which is almost
+    /// always the wrong choice in the overlapped model this type is built on.
+    ///
+    /// Note that this conflicts with the internal buffering: the read
+    /// scheduled in the background completes immediately with
+    /// `ERROR_NO_DATA` whenever the peer hasn't written anything, which is
+    /// then returned by `read` instead of a "would block" error.
+    pub fn set_nowait(&self, nowait: bool) -> io::Result<()> {
+        let (mut mode, _) = self.raw_handle_state()?;
+        set_flag(&mut mode, PIPE_NOWAIT, nowait);
+        syscall!(
+            SetNamedPipeHandleState(
+                self.as_raw_handle() as _,
+                &mut mode,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            ),
+            PartialEq::eq,
+            FALSE
+        )?;
+        Ok(())
+    }
 
-            // A read is in flight, still waiting for it to finish
-            State::Pending(buf, amt) => {
-                state.read = State::Pending(buf, amt);
-                Err(would_block())
-            }
+    /// Returns the wait and read mode flags of this named pipe along with the
+    /// number of its instances.
+    fn raw_handle_state(&self) -> io::Result<(DWORD, u32)> {
+        let mut state = 0;
+        let mut instances = 0;
+        syscall!(
+            GetNamedPipeHandleStateW(
+                self.as_raw_handle() as _,
+                &mut state,
+                &mut instances,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+            ),
+            PartialEq::eq,
+            FALSE
+        )?;
+        Ok((state, instances))
+    }
 
-            // We previously read something into `data`, try to copy out some
-            // data. If we copy out all the data schedule a new read and
-            // otherwise store the buffer to get read later.
-            State::Ok(data, cur) => {
-                let n = {
-                    let mut remaining = &data[cur..];
-                    remaining.read(buf)?
-                };
-                let next = cur + n;
-                if next != data.len() {
-                    state.read = State::Ok(data, next);
-                } else {
-                    self.inner.put_buffer(data);
-                    Inner::schedule_read(&self.inner, &mut state, None);
-                }
-                Ok(n)
-            }
+    /// Sets whether readable notifications are level-triggered.
+    ///
+    /// By default, like all other Mio sources, notifications are
+    /// edge-triggered: a single readable notification is issued once a read
+    /// completes and none follows until the data is fully drained and the next
+    /// read completes.
+    ///
+    /// In level-triggered mode another readable notification is issued each
+    /// time `read` leaves data in the internal buffer, and when the pipe is
+    /// reregistered while data or an error is waiting to be read.
+    ///
+    /// In both modes a new read is only scheduled in the background once the
+    /// internal buffer is fully drained, or once the error of the previous
+    /// read was returned. The mode is kept across reregistrations.
+    pub fn set_level_triggered(&self, level_triggered: bool) {
+        self.inner.io.lock().unwrap().level_triggered = level_triggered;
+    }
 
-            // Looks like an in-flight read hit an error, return that here while
-            // we schedule a new one.
-            State::Err(e) => {
-                Inner::schedule_read(&self.inner, &mut state, None);
-                if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
-                    Ok(0)
-                } else {
+    /// Sets the read timeout of this named pipe.
+    ///
+    /// If a read stays pending for longer than `timeout`, i.e. no data arrived
+    /// within the window, it's cancelled using `CancelIoEx` and `read`
+    /// returns an error of kind `TimedOut`. Afterwards a new read is scheduled
+    /// as usual. Passing `None` disables the timeout, passing a zero duration
+    /// returns an error.
+    ///
+    /// The deadline of an operation is fixed when it's issued, so changing the
+    /// timeout only affects operations issued afterwards.
+    ///
+    /// # Implementation
+    ///
+    /// Deadlines are enforced by a lightweight thread per pipe, spawned the
+    /// first time a timeout is set. It sleeps until the earliest pending
+    /// deadline and cancels the operation if it's still pending by then. The
+    /// thread doesn't keep the pipe alive and exits once the pipe is dropped
+    /// or all timeouts are disabled.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        let mut io = self.inner.io.lock().unwrap();
+        let old = mem::replace(&mut io.read_timeout, timeout);
+        let res = Inner::start_timer(&self.inner, &mut io);
+        if res.is_err() {
+            io.read_timeout = old;
+        }
+        res
+    }
+
+    /// Returns the read timeout set by [`set_read_timeout`].
+    ///
+    /// [`set_read_timeout`]: NamedPipe::set_read_timeout
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.inner.io.lock().unwrap().read_timeout
+    }
+
+    /// Sets the write timeout of this named pipe.
+    ///
+    /// If a write stays pending for longer than `timeout`, e.g. because the
+    /// peer doesn't read, it's cancelled and the next call to `write` returns
+    /// an error of kind `TimedOut`. Note that a cancelled write may have been
+    /// partially written. See [`set_read_timeout`] for details.
+    ///
+    /// [`set_read_timeout`]: NamedPipe::set_read_timeout
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        let mut io = self.inner.io.lock().unwrap();
+        let old = mem::replace(&mut io.write_timeout, timeout);
+        let res = Inner::start_timer(&self.inner, &mut io);
+        if res.is_err() {
+            io.write_timeout = old;
+        }
+        res
+    }
+
+    /// Returns the write timeout set by [`set_write_timeout`].
+    ///
+    /// [`set_write_timeout`]: NamedPipe::set_write_timeout
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.inner.io.lock().unwrap().write_timeout
+    }
+
+    /// Returns which operations of this named pipe are currently ready.
+    ///
+    /// A pipe registered with a single token gets one event for all of them,
+    /// this inspects the state of reads, writes and connects at once rather
+    /// than probing each. Like an event this is only a hint, e.g. a read may
+    /// complete right after this returns.
+    pub fn readiness(&self) -> Readiness {
+        let io = self.inner.io.lock().unwrap();
+        let mut readiness = Readiness::empty();
+        match io.read {
+            State::Ok(..) | State::Err(_) | State::DirectOk(_) => readiness |= Readiness::READABLE,
+            State::None | State::Pending(..) | State::DirectPending => {}
+        }
+        match io.write {
+            State::None | State::Err(_) => readiness |= Readiness::WRITABLE,
+            State::Pending(..) | State::Ok(..) | State::DirectPending | State::DirectOk(_) => {}
+        }
+        if !self.inner.connecting.load(SeqCst) {
+            readiness |= Readiness::CONNECTED;
+        }
+        readiness
+    }
+
+    /// Returns a snapshot of the internal state of this named pipe.
+    ///
+    /// This is meant for debugging, e.g. logging state transitions or
+    /// including the state in bug reports. Only the kind of each operation's
+    /// state is returned, never the internal buffers.
+    pub fn debug_state(&self) -> PipeDebugState {
+        let io = self.inner.io.lock().unwrap();
+        PipeDebugState {
+            read: io.read.debug_state(),
+            write: io.write.debug_state(),
+            connecting: self.inner.connecting.load(SeqCst),
+            connect_error: io.connect_error.is_some(),
+            eof_truncated: io.eof_truncated,
+        }
+    }
+
+    /// Returns whether this named pipe is registered with a `Poll`.
+    ///
+    /// I/O only works on a registered pipe, until then `connect`, `read` and
+    /// `write` return an error of kind `NotConnected`.
+    pub fn is_registered(&self) -> bool {
+        self.registered_token().is_some()
+    }
+
+    /// Returns the token this named pipe is registered with, if any.
+    pub fn registered_token(&self) -> Option<Token> {
+        self.inner.io.lock().unwrap().token
+    }
+
+    /// Registers all `pipes` with `registry`, e.g. a pool of server instances
+    /// created up front.
+    ///
+    /// The batch is validated before any pipe is registered: if a pipe is
+    /// already registered, in blocking or APC mode, or its token is invalid
+    /// the error is returned and none of the pipes are registered. If
+    /// registering a pipe fails after that, the pipes registered before it
+    /// are deregistered again before the error is returned. So either all
+    /// pipes are registered, or none of them are.
+    pub fn register_batch(
+        registry: &Registry,
+        pipes: &mut [(&mut NamedPipe, Token, Interest)],
+    ) -> io::Result<()> {
+        for (pipe, token, _) in pipes.iter() {
+            let io = pipe.inner.io.lock().unwrap();
+            if io.token.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "I/O source already registered with `Registry`",
+                ));
+            }
+            io.check_registrable()?;
+            pipe.inner.check_token(*token)?;
+        }
+
+        for i in 0..pipes.len() {
+            let (pipe, token, interests) = &mut pipes[i];
+            if let Err(e) = pipe.register(registry, *token, *interests) {
+                for (pipe, _, _) in pipes[..i].iter_mut() {
+                    drop(pipe.deregister(registry));
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes transferred by the last completed read.
+    ///
+    /// This is the raw transfer count of the overlapped operation, recorded
+    /// when its completion is processed by `Poll`, not what a call to `read`
+    /// returned. Returns 0 if no read completed yet.
+    pub fn last_read_len(&self) -> usize {
+        self.inner.io.lock().unwrap().last_read_len
+    }
+
+    /// Returns the number of bytes transferred by the last completed write.
+    ///
+    /// See [`last_read_len`] for details.
+    ///
+    /// [`last_read_len`]: NamedPipe::last_read_len
+    pub fn last_write_len(&self) -> usize {
+        self.inner.io.lock().unwrap().last_write_len
+    }
+
+    /// Returns whether the last write issued by `write`, [`try_write`] or
+    /// [`write_all_bufs`] completed synchronously, i.e. at least part of
+    /// the data went out right away rather than being queued until the
+    /// completion is processed by `Poll`.
+    ///
+    /// Writes being queued regularly hints at the peer not keeping up, or the
+    /// pipe's buffer being too small for the writes. Returns `false` if no
+    /// write was issued yet.
+    ///
+    /// [`try_write`]: NamedPipe::try_write
+    /// [`write_all_bufs`]: NamedPipe::write_all_bufs
+    pub fn last_write_was_synchronous(&self) -> bool {
+        self.inner.io.lock().unwrap().last_write_sync
+    }
+
+    /// Returns how many reads and writes returned a "would block" error as an
+    /// internal operation was still in flight.
+    ///
+    /// Event loops only read or write after a readiness event, so a quickly
+    /// growing count points at one calling I/O without waiting for readiness.
+    /// Note that with [`WriteFullPolicy::Block`] every wake up of a blocked
+    /// write counts.
+    pub fn would_block_count(&self) -> u64 {
+        self.inner.io.lock().unwrap().would_block_count
+    }
+
+    /// Resets the count returned by [`would_block_count`], returning its
+    /// previous value.
+    ///
+    /// [`would_block_count`]: NamedPipe::would_block_count
+    pub fn reset_would_block_count(&self) -> u64 {
+        mem::replace(&mut self.inner.io.lock().unwrap().would_block_count, 0)
+    }
+
+    /// Returns the total number of bytes read from this named pipe.
+    ///
+    /// This counts the bytes transferred by completed reads, including ones
+    /// still buffered internally, and by the blocking reads.
+    pub fn bytes_read(&self) -> u64 {
+        self.inner.bytes_read.load(Relaxed)
+    }
+
+    /// Returns the total number of bytes written to this named pipe.
+    ///
+    /// This counts the bytes transferred by completed writes, not those
+    /// accepted by `write` but still in flight, and by the blocking writes.
+    pub fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written.load(Relaxed)
+    }
+
+    /// Resets [`bytes_read`] and [`bytes_written`], returning their previous
+    /// values, e.g. to sample the throughput periodically.
+    ///
+    /// [`bytes_read`]: NamedPipe::bytes_read
+    /// [`bytes_written`]: NamedPipe::bytes_written
+    pub fn reset_counters(&self) -> (u64, u64) {
+        (
+            self.inner.bytes_read.swap(0, Relaxed),
+            self.inner.bytes_written.swap(0, Relaxed),
+        )
+    }
+
+    /// Returns the raw OS error code of the most recent overlapped read or
+    /// write that failed, e.g. `ERROR_BROKEN_PIPE`.
+    ///
+    /// This allows matching Win32 error codes that don't map to an
+    /// `io::ErrorKind`, see [`PipeError::classify`] for the common ones. The
+    /// code is kept after the error itself is returned, and it's `None` if no
+    /// operation failed yet or the most recent failure wasn't an OS error,
+    /// e.g. a write timing out.
+    pub fn last_os_error_code(&self) -> Option<i32> {
+        self.inner.io.lock().unwrap().last_os_error
+    }
+
+    /// Returns how many bytes of the write in flight are still pending.
+    ///
+    /// While this returns `Some` writing returns a "would block" error. This
+    /// allows a caller to decide between waiting for the pipe to become
+    /// writable and dropping a message, e.g. if the peer isn't keeping up.
+    /// Returns `None` if no write is in flight. Writes issued with
+    /// [`write_overlapped_from`] don't track their length and are reported as
+    /// `Some(0)`.
+    ///
+    /// [`write_overlapped_from`]: NamedPipe::write_overlapped_from
+    pub fn writable_after(&self) -> Option<usize> {
+        match self.inner.io.lock().unwrap().write {
+            State::Pending(ref buf, pos) => Some(buf.len() - pos),
+            // Completed immediately or directly, waiting for the completion.
+            State::Ok(..) | State::DirectPending => Some(0),
+            State::None | State::Err(_) | State::DirectOk(_) => None,
+        }
+    }
+
+    /// Returns the capacity of the buffer held for the internal read.
+    ///
+    /// Returns the default capacity of newly allocated buffers if no buffer
+    /// is held, i.e. while no read is in flight or buffered.
+    pub fn read_buffer_capacity(&self) -> usize {
+        self.inner.io.lock().unwrap().read.buffer_capacity()
+    }
+
+    /// Returns the capacity of the buffer held for the write in flight.
+    ///
+    /// Returns the default capacity of newly allocated buffers if no buffer
+    /// is held, note that a write copies into a buffer at least as large as
+    /// the data written.
+    pub fn write_buffer_capacity(&self) -> usize {
+        self.inner.io.lock().unwrap().write.buffer_capacity()
+    }
+
+    /// Clears a stuck connecting state, along with a stale connect error.
+    ///
+    /// This is a recovery escape hatch: if a connect was issued outside of
+    /// this type, e.g. using the raw handle, [`connect`] can keep returning a
+    /// "would block" error as it believes a connect is still in progress.
+    ///
+    /// [`connect`]: NamedPipe::connect
+    ///
+    /// # Safety
+    ///
+    /// No connect issued by [`connect`] may be in flight, its completion would
+    /// then race with the next connect for the same `OVERLAPPED` structure.
+    pub unsafe fn reset_connecting(&self) {
+        self.inner.connecting.store(false, SeqCst);
+        let mut io = self.inner.io.lock().unwrap();
+        io.connect_error = None;
+        io.connect_retry = None;
+    }
+
+    /// Checks whether a connect issued by [`connect`] has completed.
+    ///
+    /// Returns `Ok(false)` while the connect is still in progress and
+    /// `Ok(true)` once a client is connected, i.e. the connect completed or
+    /// found a client already connected. If the connect failed its error is
+    /// returned, and taken, as [`take_error`] would. This combines waiting
+    /// for the writable notification and calling `take_error` into one call.
+    /// Also returns `Ok(false)` if no connect was issued since the pipe was
+    /// created or last disconnected.
+    ///
+    /// [`connect`]: NamedPipe::connect
+    /// [`take_error`]: NamedPipe::take_error
+    pub fn poll_connected(&self) -> io::Result<bool> {
+        if self.inner.connecting.load(SeqCst) {
+            return Ok(false);
+        }
+
+        let mut io = self.inner.io.lock().unwrap();
+        match io.connect_error.take() {
+            Some(e) => Err(e),
+            None => Ok(io.connected),
+        }
+    }
+
+    /// Returns a future resolving once a connect issued by [`connect`] has
+    /// completed.
+    ///
+    /// This is [`poll_connected`] packaged as a `Future`: it resolves to
+    /// `Ok(())` once a client is connected and to the error of the connect if
+    /// it failed. The pipe must be registered with a `Poll` that keeps being
+    /// polled, the completion is processed there and wakes the task. Resolves
+    /// right away if a client is already connected, and never if no connect
+    /// was issued.
+    ///
+    /// [`connect`]: NamedPipe::connect
+    /// [`poll_connected`]: NamedPipe::poll_connected
+    #[cfg(feature = "future")]
+    pub fn connected(&self) -> Connected<'_> {
+        Connected { pipe: self }
+    }
+
+    /// Finishes accepting a client after a [`connect`], moving this pipe to
+    /// `token`.
+    ///
+    /// This checks the connect completed successfully (see
+    /// [`poll_connected`]), reregisters the pipe under `token` and schedules
+    /// the first read, which is what servers usually do once a client
+    /// connected to their listening instance.
+    ///
+    /// Returns a "would block" error if the connect hasn't completed yet, or
+    /// the error of the connect if it failed.
+    ///
+    /// [`connect`]: NamedPipe::connect
+    /// [`poll_connected`]: NamedPipe::poll_connected
+    pub fn accept_ready(&self, registry: &Registry, token: Token) -> io::Result<()> {
+        if !self.poll_connected()? {
+            return Err(would_block());
+        }
+
+        let mut io = self.inner.io.lock().unwrap();
+        io.check_association(registry, true)?;
+        io.check_registrable()?;
+        self.inner.check_token(token)?;
+        io.token = Some(token);
+        drop(io);
+
+        Inner::post_register(&self.inner, None);
+        Ok(())
+    }
+
+    /// Creates a [`Waker`] posting to the completion port this named pipe is
+    /// registered with.
+    ///
+    /// This lets a background thread nudge the event loop, e.g. to re-check
+    /// a pipe whose read it just refreshed or which it recycled. Wakeups are
+    /// independent of the pipe's own events: they're posted to the port
+    /// directly and reported as a readable event for `token`, which doesn't
+    /// change the pipe's readiness. Passing the pipe's own token makes the
+    /// event loop handle the pipe again, where a `read` returning "would
+    /// block" is expected then.
+    ///
+    /// Returns an error if the pipe isn't registered with `registry`. Note
+    /// that only a single `Waker` may be active per `Poll`.
+    pub fn waker(&self, registry: &Registry, token: Token) -> io::Result<Waker> {
+        let io = self.inner.io.lock().unwrap();
+        io.check_association(registry, true)?;
+        if io.token.is_none() {
+            return Err(not_registered());
+        }
+        drop(io);
+        Waker::new(registry, token)
+    }
+
+    /// Takes the error that ended the stream the last time `read` returned
+    /// `Ok(0)`.
+    ///
+    /// `read` reports the peer leaving, e.g. `ERROR_BROKEN_PIPE`, as an
+    /// end-of-file. This returns the underlying error, if it hasn't been taken
+    /// yet. Use [`debug_state`] to check whether the stream ended in the
+    /// middle of a message, rather than at a message boundary.
+    ///
+    /// [`debug_state`]: NamedPipe::debug_state
+    pub fn take_read_error(&self) -> Option<io::Error> {
+        self.inner.io.lock().unwrap().eof_error.take()
+    }
+
+    /// Disconnects this named pipe from a connected client.
+    ///
+    /// This function will disconnect the pipe from a connected client, if any,
+    /// transitively calling the `DisconnectNamedPipe` function.
+    ///
+    /// After a `disconnect` is issued, then a `connect` may be called again to
+    /// connect to another client.
+    ///
+    /// Unlike the other operations this is synchronous, so it doesn't require
+    /// the pipe to be registered. It doesn't block the event loop though:
+    /// `DisconnectNamedPipe` never waits for the client, which is why there's
+    /// no overlapped variant of it.
+    ///
+    /// Instead any data the client hasn't read yet is discarded, including
+    /// data of a write still in flight. To flush before disconnecting wait
+    /// until [`writable_after`] returns `None`, that is the pipe is writable
+    /// again. That still only means the data reached the system's buffer, if
+    /// the client must have read it the protocol needs an acknowledgement.
+    ///
+    /// [`writable_after`]: NamedPipe::writable_after
+    pub fn disconnect(&self) -> io::Result<()> {
+        self.inner.handle.disconnect()?;
+        self.inner.io.lock().unwrap().connected = false;
+        self.inner.fire(PipeEvent::Disconnected);
+        Ok(())
+    }
+
+    /// Checks whether the other end of the pipe is still connected, without
+    /// consuming any data.
+    ///
+    /// This does a zero-byte `PeekNamedPipe`, returning `false` if the other
+    /// end went away or, on the server end, no client is connected yet. It's
+    /// best-effort only: the other end can go away at any instant after the
+    /// check.
+    pub fn is_peer_connected(&self) -> io::Result<bool> {
+        let res = syscall!(
+            PeekNamedPipe(
+                self.as_raw_handle() as HANDLE,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            ),
+            PartialEq::eq,
+            FALSE
+        );
+        match res {
+            Ok(_) => Ok(true),
+            Err(e) => match PipeError::classify(&e) {
+                PipeError::BrokenPipe | PipeError::Listening | PipeError::NoData => Ok(false),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Returns the number of bytes waiting in the system's buffer of the pipe,
+    /// without consuming them.
+    ///
+    /// This wraps `PeekNamedPipe`, reading `lpTotalBytesAvail`. It doesn't
+    /// count the data already read into this pipe's internal buffer, and
+    /// while a read is in flight incoming data completes that read first, so
+    /// this mostly reports what arrived beyond the read in flight, e.g. when
+    /// reads are capped by [`NamedPipeBuilder::max_read_ahead`]. It doesn't
+    /// disturb the read in flight, but the result is only a snapshot: the
+    /// read can take the data right after this returns.
+    pub fn peek_available(&self) -> io::Result<usize> {
+        let mut available = 0;
+        syscall!(
+            PeekNamedPipe(
+                self.as_raw_handle() as HANDLE,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                &mut available,
+                ptr::null_mut(),
+            ),
+            PartialEq::eq,
+            FALSE
+        )?;
+        Ok(available as usize)
+    }
+
+    /// Sets the event handles signalled when the overlapped operations of this
+    /// named pipe complete, replacing (and closing) the ones set previously.
+    ///
+    /// By default no events are used and completions are only reported to
+    /// the completion port. With events e.g. an auto-reset or named event
+    /// shared with another component can be signalled as well. The pipe owns
+    /// the events and closes them when dropped.
+    ///
+    /// This must be done before the pipe is registered, an error is returned
+    /// if it is registered or any operation is in flight.
+    pub fn set_overlapped_events(&self, events: OverlappedEvents) -> io::Result<()> {
+        let io = self.inner.io.lock().unwrap();
+        self.inner.check_idle(&io)?;
+
+        fn raw(event: &Option<OwnedEvent>) -> HANDLE {
+            event.as_ref().map_or(ptr::null_mut(), |event| event.0)
+        }
+        // Safety: no operation is in flight, so nothing else accesses the
+        // `OVERLAPPED` structures. `io` stays locked, so none is issued.
+        unsafe {
+            (*self.inner.connect.as_mut_ptr()).hEvent = raw(&events.connect);
+            (*self.inner.read.as_mut_ptr()).hEvent = raw(&events.read);
+            (*self.inner.write.as_mut_ptr()).hEvent = raw(&events.write);
+        }
+        *self.inner.events.lock().unwrap() = events;
+        Ok(())
+    }
+
+    /// Switches this named pipe between blocking and non-blocking mode.
+    ///
+    /// In blocking mode `read` and `write` bypass the internal buffering and
+    /// wait for the operation to finish like [`read_blocking`] and
+    /// [`write_blocking`], honouring the timeouts set by [`set_read_timeout`]
+    /// and [`set_write_timeout`]. This is meant for synchronous phases, e.g. a
+    /// handshake before the pipe is handed to an event loop. Entering
+    /// blocking mode also puts the handle into `PIPE_WAIT` mode, see
+    /// [`set_nowait`]. Leaving it doesn't change the wait mode, the
+    /// non-blocking behaviour of this type comes from overlapped I/O.
+    ///
+    /// The pipe must be in non-blocking mode (the default) when it's
+    /// registered with `Poll`, which fails otherwise. Entering blocking mode
+    /// fails while the pipe is registered or has operations in flight, so no
+    /// data buffered by an earlier overlapped read is skipped.
+    ///
+    /// [`read_blocking`]: NamedPipe::read_blocking
+    /// [`write_blocking`]: NamedPipe::write_blocking
+    /// [`set_read_timeout`]: NamedPipe::set_read_timeout
+    /// [`set_write_timeout`]: NamedPipe::set_write_timeout
+    /// [`set_nowait`]: NamedPipe::set_nowait
+    pub fn set_blocking(&self, blocking: bool) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+        if blocking && !io.blocking {
+            self.inner.check_idle(&io)?;
+            self.set_nowait(false)?;
+        }
+        io.blocking = blocking;
+        Ok(())
+    }
+
+    /// Returns a guard which disconnects the client once it's dropped.
+    ///
+    /// This ties the lifetime of a client session to a scope, so the client
+    /// is disconnected even if the handler returns early or panics. Errors of
+    /// the disconnect are ignored. The guard dereferences to the pipe.
+    pub fn connection_guard(&self) -> ConnectionGuard<'_> {
+        ConnectionGuard { pipe: self }
+    }
+
+    /// Starts reading from the named pipe if it was created without the
+    /// initial read, see [`NamedPipeBuilder::initial_read`].
+    ///
+    /// Schedules the first read if the pipe is registered, otherwise it's
+    /// scheduled on registration. Does nothing if reading already started.
+    pub fn start_reading(&self) {
+        let mut io = self.inner.io.lock().unwrap();
+        if mem::replace(&mut io.reading_deferred, false) && io.token.is_some() {
+            Inner::schedule_read(&self.inner, &mut io, None);
+        }
+    }
+
+    /// Cancels the read in flight and schedules a fresh one.
+    ///
+    /// A read stays pending for as long as the client neither writes nor
+    /// disconnects. This resets the read slot, e.g. from a watchdog after an
+    /// application level timeout, without dropping the pipe. If the read
+    /// completes before it's cancelled its data is kept as usual. Does
+    /// nothing if no internal read is in flight.
+    pub fn refresh_read(&self) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+        if let State::Pending(..) = io.read {
+            io.read_refreshed = true;
+            // `read_done` reclaims the reference held by the read and
+            // schedules the next one.
+            unsafe { cancel(&self.inner.handle, &self.inner.read)? };
+        }
+        Ok(())
+    }
+
+    /// Sets whether the handle of this named pipe can be inherited by child
+    /// processes.
+    ///
+    /// To hand the pipe to a worker process, make it inheritable and pass
+    /// `TRUE` for `bInheritHandles` to `CreateProcess`, e.g. as the standard
+    /// input or output in `STARTUPINFO` (with `STARTF_USESTDHANDLES`) or by
+    /// passing the raw handle value on the command line. Keep the
+    /// `NamedPipe` alive until the child was spawned. The child receives a
+    /// handle opened for overlapped I/O, which it must use with `OVERLAPPED`
+    /// structures.
+    ///
+    /// Note that every child spawned while the handle is inheritable
+    /// inherits it, including ones spawned by other threads.
+    pub fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+        syscall!(
+            SetHandleInformation(self.as_raw_handle() as HANDLE, HANDLE_FLAG_INHERIT, flags),
+            PartialEq::eq,
+            FALSE
+        )?;
+        Ok(())
+    }
+
+    /// Takes the data buffered by the last internal read as an owned buffer,
+    /// rather than copying it out like `read`.
+    ///
+    /// Returns the data not consumed by `read` yet and schedules the next
+    /// read. The buffer is handed off for good, it doesn't return to the
+    /// buffer pool, so the next read allocates a new one. Returns a "would
+    /// block" error while no data is buffered and `Ok(None)` once the peer
+    /// disconnected.
+    pub fn take_read_buffer(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.check_access(PipeAccess::Inbound)?;
+        self.inner.check_buffered("read_overlapped_into")?;
+        let mut io = self.inner.io.lock().unwrap();
+
+        if !io.is_active() {
+            return Err(not_registered());
+        }
+
+        match mem::replace(&mut io.read, State::None) {
+            State::Ok(mut data, cur) => {
+                if cur != 0 {
+                    // Keep the (larger) original buffer in the pool.
+                    let rest = data.split_off(cur);
+                    self.inner.put_buffer(mem::replace(&mut data, rest));
+                }
+                Inner::schedule_read(&self.inner, &mut io, None);
+                Ok(Some(data))
+            }
+            State::Err(e) => {
+                Inner::schedule_read(&self.inner, &mut io, None);
+                if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
+                    io.eof_truncated = io.mid_message;
+                    io.eof_error = Some(e);
+                    Ok(None)
+                } else {
                     Err(e)
                 }
             }
+            s => {
+                io.read = s;
+                io.would_block_count += 1;
+                Err(would_block())
+            }
+        }
+    }
+
+    /// Switches this named pipe to deliver the completions of its reads and
+    /// writes as APCs (asynchronous procedure calls) instead of through a
+    /// completion port.
+    ///
+    /// This is for programs built around alertable waits rather than `Poll`.
+    /// Reads and writes are then issued using `ReadFileEx` and `WriteFileEx`,
+    /// whose completion routines run on the thread that issued them once it
+    /// enters an alertable wait, e.g. [`run_alertable`]. All I/O on the pipe
+    /// must therefore happen on the thread calling `run_alertable`. The pipe
+    /// must be connected already, connects use a completion port and fail in
+    /// APC mode. `WriteFullPolicy::Block` can't be used either, it would wait
+    /// for an APC that can't run.
+    ///
+    /// This can't be undone. It fails if the pipe is registered or has
+    /// operations in flight, and registering the pipe afterwards fails.
+    ///
+    /// [`run_alertable`]: NamedPipe::run_alertable
+    pub fn set_apc_mode(&self) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+        self.inner.check_idle(&io)?;
+        io.apc = true;
+        Ok(())
+    }
+
+    /// Performs an alertable wait of up to `timeout` (forever if `None`),
+    /// running the completion routines of the operations issued in APC mode.
+    ///
+    /// Schedules a read first, if none is in flight yet. Returns `true` if
+    /// completion routines ran, which may include ones of other APCs queued
+    /// to the calling thread, and `false` if the wait timed out. See
+    /// [`set_apc_mode`].
+    ///
+    /// [`set_apc_mode`]: NamedPipe::set_apc_mode
+    pub fn run_alertable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut io = self.inner.io.lock().unwrap();
+        if !io.apc {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "named pipe is not in APC mode",
+            ));
+        }
+        Inner::schedule_read(&self.inner, &mut io, None);
+        // The completion routines lock `io`.
+        drop(io);
+        Ok(unsafe { SleepEx(timeout_ms(timeout), TRUE) } == WAIT_IO_COMPLETION)
+    }
+
+    /// Associates this named pipe with the completion port `iocp` under the
+    /// completion key `key`, rather than registering it with a `Registry`.
+    ///
+    /// This is for embedders running their own completion port loop. The
+    /// completions of the pipe's operations are then dequeued by that loop,
+    /// which must pass each of them to [`dispatch_completion`]. Readiness
+    /// events aren't delivered, use e.g. [`readiness`] after dispatching.
+    /// Reads are scheduled right away, the other operations work like on a
+    /// registered pipe.
+    ///
+    /// A handle can only be associated with a single completion port, for
+    /// good: this fails if the pipe is already registered or associated, and
+    /// registering the pipe afterwards fails. It also fails in blocking or APC
+    /// mode, which don't use a completion port.
+    ///
+    /// [`readiness`]: NamedPipe::readiness
+    pub fn associate_with_iocp(&self, iocp: RawHandle, key: usize) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+        if io.selector.is_some() || io.foreign_port {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "named pipe is already associated with a completion port",
+            ));
+        }
+        if io.blocking || io.apc {
+            return Err(blocking_mode());
+        }
+        syscall!(
+            CreateIoCompletionPort(self.as_raw_handle() as HANDLE, iocp as HANDLE, key, 0),
+            PartialEq::eq,
+            ptr::null_mut()
+        )?;
+        io.foreign_port = true;
+        drop(io);
+
+        Inner::post_register(&self.inner, None);
+        Ok(())
+    }
+
+    /// Returns an adapter reading whole messages of a message mode pipe, see
+    /// [`Messages::next_message`].
+    ///
+    /// Messages larger than the internal buffer are completed with
+    /// `ERROR_MORE_DATA` in several parts, which the adapter joins. Reading
+    /// from the pipe directly while using the adapter mixes up messages.
+    pub fn messages(&self) -> Messages<'_> {
+        Messages {
+            pipe: self,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Discards any data buffered for a previous client.
+    ///
+    /// Buffered reads are returned to the pool and the read and write state
+    /// is reset, so a client connected afterwards doesn't observe stale bytes
+    /// or a stale end-of-file from the previous connection.
+    ///
+    /// This must only be called between connections, i.e. after `disconnect`
+    /// and before the next `connect`. Operations still in flight are left
+    /// alone as the kernel owns their buffers, so this should be called once
+    /// a `read` returned `Ok(0)` to signal the previous client left.
+    pub fn reset_buffers(&self) {
+        let mut io = self.inner.io.lock().unwrap();
+
+        match mem::replace(&mut io.read, State::None) {
+            State::Ok(buf, _) => self.inner.put_buffer(buf),
+            // Completions for these will still come in through `read_done`.
+            state @ State::Pending(..) | state @ State::DirectPending => io.read = state,
+            _ => {}
+        }
+
+        // A successful immediate write still has a completion coming in, only
+        // errors can be cleared here.
+        if let State::Err(_) = io.write {
+            io.write = State::None;
+        }
+
+        // Start the next operations from a clean `OVERLAPPED`, a leftover
+        // signaled event would make them look complete right away.
+        // Safety: the state shows no operation is in flight, `io` stays
+        // locked so none is issued meanwhile.
+        unsafe {
+            if let State::None = io.read {
+                self.inner.read.reset();
+            }
+            if let State::None = io.write {
+                self.inner.write.reset();
+            }
+            if !self.inner.connecting.load(SeqCst) {
+                self.inner.connect.reset();
+            }
+        }
+    }
+
+    /// Disconnects the current client and readies this pipe for the next one.
+    ///
+    /// This disconnects, resets all internal state (see [`reset_buffers`]),
+    /// clears any stored connect error and issues a fresh [`connect`]. Unlike
+    /// [`reset_buffers`] this may be called while operations are in flight:
+    /// their completions belong to the previous client and are discarded.
+    ///
+    /// Like `connect` this returns a "would block" error if the next client
+    /// isn't connected immediately.
+    ///
+    /// [`reset_buffers`]: NamedPipe::reset_buffers
+    /// [`connect`]: NamedPipe::connect
+    pub fn recycle(&self) -> io::Result<()> {
+        self.disconnect()?;
+
+        let mut io = self.inner.io.lock().unwrap();
+        if let State::Pending(..) = io.read {
+            io.discard_read = true;
+        }
+        if let State::Pending(..) = io.write {
+            io.discard_write = true;
+        }
+        io.connect_error = None;
+        drop(io);
+
+        self.reset_buffers();
+        self.connect()
+    }
+
+    /// Writes all of `bufs` using a single overlapped write.
+    ///
+    /// All slices are copied into one internal buffer which is submitted in
+    /// one go, so unlike looping over `write_vectored` this never stops
+    /// halfway through the slices. A "would block" error is only returned if a
+    /// previous write is still in flight, in which case nothing is written.
+    ///
+    /// Note that there's no zero-copy alternative based on `WriteFileGather`
+    /// and `ReadFileScatter`: those only work on files opened with
+    /// `FILE_FLAG_NO_BUFFERING`, which named pipes don't support. For bulk
+    /// transfers without the copy see [`NamedPipeBuilder::direct_mode`].
+    pub fn write_all_bufs(&self, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+        self.inner.check_access(PipeAccess::Outbound)?;
+        self.inner.check_buffered("write_overlapped_from")?;
+        self.inner
+            .check_message_size(bufs.iter().map(|b| b.len()).sum())?;
+        let mut io = self.inner.io.lock().unwrap();
+        io.check_writable()?;
+
+        let (owned_buf, pos) = self.inner.buffer_from(bufs);
+        let res = Inner::maybe_schedule_write(&self.inner, owned_buf, pos, &mut io)?;
+        io.last_write_sync = res.is_some();
+        if res.is_some() {
+            // Leave writing whatever didn't complete immediately to
+            // `write_done`, see `Inner::schedule_write`.
+            io.write = match mem::replace(&mut io.write, State::None) {
+                State::Ok(buf, pos) => State::Pending(buf, pos),
+                // `io` is locked, so this branch is unreachable
+                _ => unreachable!(),
+            };
+        }
+        Ok(())
+    }
+
+    /// Writes `request` and reads the response into `response` in a single
+    /// operation, wrapping `TransactNamedPipe`.
+    ///
+    /// This is meant for request/response protocols on message mode pipes and
+    /// returns an error of kind `InvalidInput` if the pipe isn't in message
+    /// read mode. The call blocks for up to `timeout` (forever if `None`) and
+    /// returns an error of kind `TimedOut` if no response arrived in time.
+    /// Returns the length of the response, if it didn't fit `response` an
+    /// error with the `ERROR_MORE_DATA` code is returned and the rest can be
+    /// read as usual.
+    ///
+    /// The internal buffering is bypassed. As the system refuses a transaction
+    /// while a read is in flight this returns a "would block" error in that
+    /// case, so this should be used with a pipe in [direct mode] or one
+    /// registered without readable interest. Similarly, a write still in
+    /// flight is written before `request`.
+    ///
+    /// [direct mode]: NamedPipeBuilder::direct_mode
+    pub fn transact(
+        &self,
+        request: &[u8],
+        response: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<usize> {
+        if !self.handle_state()?.message_read_mode {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "named pipe is not in message read mode",
+            ));
+        }
+        self.inner.check_message_size(request.len())?;
+        match self.inner.io.lock().unwrap().read {
+            State::None => {}
+            _ => return Err(would_block()),
+        }
+
+        let mut overlapped = SyncOverlapped::new()?;
+        let ret = unsafe {
+            TransactNamedPipe(
+                self.as_raw_handle() as HANDLE,
+                request.as_ptr() as *mut _,
+                request.len() as DWORD,
+                response.as_mut_ptr() as *mut _,
+                response.len() as DWORD,
+                ptr::null_mut(),
+                overlapped.as_mut_ptr(),
+            )
+        };
+        if ret == FALSE {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                return Err(err);
+            }
+        }
+        overlapped.wait(&self.inner.handle, timeout, "transaction")
+    }
+
+    /// Reads as much of `buf` as is available without waiting, a building
+    /// block for reading fixed-size frames.
+    ///
+    /// Unlike `Read::read_exact`, which fails on a "would block" error, this
+    /// makes partial progress: it returns the number of bytes read, which is
+    /// less than `buf.len()` if no more data was available. If nothing is
+    /// available it returns a "would block" error, the caller then waits for
+    /// the next readable event and calls this again with the rest of `buf`,
+    /// i.e. `&mut buf[filled..]`, until the frame is complete.
+    ///
+    /// Returns an error of kind `UnexpectedEof` if the stream ended before
+    /// anything was read. Bytes read before the end of the stream are
+    /// returned first.
+    pub fn read_exact_ready(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match Inner::read(&self.inner, &mut buf[filled..]) {
+                Ok(0) if filled == 0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "named pipe stream ended before the buffer was filled",
+                    ))
+                }
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && filled > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Reads into `buf`, blocking the calling thread for up to `timeout`
+    /// (forever if `None`).
+    ///
+    /// This is meant for simple tools and tests without an event loop, so the
+    /// pipe doesn't need to be registered. The read is issued directly against
+    /// `buf`, bypassing the internal buffering, and waited on synchronously.
+    /// Returns an error of kind `TimedOut` if nothing was read in time and
+    /// `Ok(0)` once the client disconnected.
+    ///
+    /// Mixing blocking and `Poll` driven I/O on the same pipe is unsupported,
+    /// the order in which data is received is then unspecified.
+    pub fn read_blocking(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.inner.check_access(PipeAccess::Inbound)?;
+        self.inner.read_sync(buf, timeout)
+    }
+
+    /// Writes `buf`, blocking the calling thread for up to `timeout` (forever
+    /// if `None`).
+    ///
+    /// See [`read_blocking`] for details.
+    ///
+    /// [`read_blocking`]: NamedPipe::read_blocking
+    pub fn write_blocking(&self, buf: &[u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.inner.check_access(PipeAccess::Outbound)?;
+        self.inner.check_message_size(buf.len())?;
+        self.inner.write_sync(buf, timeout)
+    }
+
+    /// Reads into `buf`, waiting for up to `timeout` for the pending read to
+    /// complete if nothing is buffered yet.
+    ///
+    /// Unlike [`set_read_timeout`] this doesn't cancel anything: if the read
+    /// is still in flight once `timeout` elapsed an error of kind `TimedOut`
+    /// is returned and the read completes as usual later on. It's meant for a
+    /// per call deadline on a registered pipe without configuring one for all
+    /// reads. A pipe in blocking mode reads synchronously with `timeout`
+    /// instead, like [`read_blocking`].
+    ///
+    /// Completions are processed by `Poll`, so another thread must be polling
+    /// the pipe for this to return anything but buffered data or `TimedOut`.
+    ///
+    /// # Single consumer
+    ///
+    /// This takes data from the same buffer as the `Read` implementation, and
+    /// the readiness event for the completed read is delivered regardless. A
+    /// poll driven reader woken up by that event may thus find the data gone
+    /// and get a "would block" error. Only one party should consume data from
+    /// the pipe at a time.
+    ///
+    /// [`set_read_timeout`]: NamedPipe::set_read_timeout
+    /// [`read_blocking`]: NamedPipe::read_blocking
+    pub fn read_timeout_once(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.read_until(buf, Some(Instant::now() + timeout))
+    }
+
+    /// Waits for a client to connect and reads the first data it sends into
+    /// `buf`, giving up after `timeout` (never if `None`).
+    ///
+    /// This is the usual preamble of request/response servers: accept a
+    /// client, then read its request. A registered pipe issues a [`connect`]
+    /// if none is in progress and waits for its completion, then reads like
+    /// [`read_timeout_once`], including its single consumer requirement. As
+    /// completions are processed by `Poll`, another thread must be polling
+    /// the pipe. A pipe in blocking mode (see [`set_blocking`]) connects and
+    /// reads synchronously instead, for setup phases without an event loop.
+    ///
+    /// Errors of the connect, including it timing out, are returned wrapped
+    /// in an `io::Error` of the same kind whose [`get_ref`] is the original
+    /// error, while errors of the read are returned as is. A connect that
+    /// timed out isn't cancelled for a registered pipe, it completes once a
+    /// client connects.
+    ///
+    /// [`connect`]: NamedPipe::connect
+    /// [`read_timeout_once`]: NamedPipe::read_timeout_once
+    /// [`set_blocking`]: NamedPipe::set_blocking
+    /// [`get_ref`]: io::Error::get_ref
+    pub fn accept_and_read(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        if self.inner.io.lock().unwrap().blocking {
+            self.inner.connect_sync(timeout).map_err(connect_failed)?;
+        } else {
+            self.wait_connected(deadline).map_err(connect_failed)?;
+        }
+        self.read_until(buf, deadline)
+    }
+
+    /// Issues a connect if none is in progress and waits until it completed,
+    /// see `accept_and_read`.
+    fn wait_connected(&self, deadline: Option<Instant>) -> io::Result<()> {
+        match self.connect() {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        let mut io = self.inner.io.lock().unwrap();
+        while self.inner.connecting.load(SeqCst) {
+            io = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Err(timed_out("connect"));
+                    }
+                    let cond = &self.inner.connect_cond;
+                    cond.wait_timeout(io, deadline - now).unwrap().0
+                }
+                None => self.inner.connect_cond.wait(io).unwrap(),
+            };
+        }
+        match io.connect_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Implementation of `read_timeout_once`, waiting forever without a
+    /// `deadline`.
+    fn read_until(&self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize> {
+        let inner = &self.inner;
+        if inner.io.lock().unwrap().blocking {
+            inner.check_access(PipeAccess::Inbound)?;
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            return inner.read_sync(buf, timeout);
+        }
+        loop {
+            match Inner::read(inner, buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            let io = inner.io.lock().unwrap();
+            match io.read {
+                State::Pending(..) => {}
+                // The read completed between the attempt above and locking
+                // `io`, try again.
+                State::Ok(..) | State::Err(_) => continue,
+                // No read in flight to wait on.
+                _ => return Err(would_block()),
+            }
+            let io = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Err(timed_out("read"));
+                    }
+                    inner.read_cond.wait_timeout(io, deadline - now).unwrap().0
+                }
+                None => inner.read_cond.wait(io).unwrap(),
+            };
+            drop(io);
+        }
+    }
+
+    /// Writes only what the system accepts immediately from `buf`.
+    ///
+    /// Returns the number of bytes the overlapped write completed with
+    /// synchronously. If the write can't complete immediately it's cancelled
+    /// and a "would block" error is returned, where the `Write` implementation
+    /// would keep the write in flight and report the whole slice as written.
+    /// The pipe is flagged as writable again once the cancellation completed.
+    ///
+    /// If the cancelled write turns out to have transferred some bytes anyway,
+    /// the next write returns an error as the stream is no longer in sync with
+    /// what the caller was told.
+    pub fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.check_access(PipeAccess::Outbound)?;
+        self.inner.check_buffered("write_overlapped_from")?;
+        self.inner.check_message_size(buf.len())?;
+        let mut io = self.inner.io.lock().unwrap();
+        io.check_writable()?;
+
+        let (owned_buf, pos) = self.inner.buffer_from(&[IoSlice::new(buf)]);
+        let res = Inner::maybe_schedule_write(&self.inner, owned_buf, pos, &mut io)?;
+        io.last_write_sync = res.is_some();
+        match res {
+            Some(n) => Ok(n),
+            None => {
+                io.retract_write = true;
+                // Safety: `write` is the overlapped of the pending write. If it
+                // completed in the meantime `write_done` reports it.
+                let res = unsafe { cancel(&self.inner.handle, &self.inner.write) };
+                debug_assert!(res.is_ok(), "failed to cancel write: {:?}", res);
+                Err(would_block())
+            }
+        }
+    }
+
+    /// Issues an overlapped read directly into `buf`, bypassing the internal
+    /// buffering.
+    ///
+    /// This is an opt-in zero-copy path for bulk transfers. The read is only
+    /// issued if no other read is in flight, otherwise a "would block" error
+    /// is returned. Note that a registered pipe eagerly schedules internal
+    /// reads, and `read` schedules the next one as soon as the previous one
+    /// was drained. Create the pipe without the [initial read] and issue the
+    /// direct read before calling [`start_reading`], or use [direct mode].
+    ///
+    /// Once the read completes this pipe is flagged as readable and
+    /// [`take_overlapped_read`] returns the number of bytes written into
+    /// `buf`. Until then `read` returns a "would block" error.
+    ///
+    /// [`take_overlapped_read`]: NamedPipe::take_overlapped_read
+    /// [initial read]: NamedPipeBuilder::initial_read
+    /// [`start_reading`]: NamedPipe::start_reading
+    /// [direct mode]: NamedPipeBuilder::direct_mode
+    ///
+    /// # Safety
+    ///
+    /// The kernel writes into `buf` *after* this function returns. The caller
+    /// must ensure the memory behind `buf` is neither moved, freed nor
+    /// otherwise accessed until `take_overlapped_read` returns something other
+    /// than a "would block" error. If the pipe is dropped before that the read
+    /// is cancelled, but `buf` must stay valid until the cancellation
+    /// completion has been processed by `Poll`.
+    pub unsafe fn read_overlapped_into(&self, mut buf: Pin<&mut [u8]>) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+
+        if !io.is_active() {
+            return Err(not_registered());
+        }
+
+        match io.read {
+            State::None => {}
+            _ => return Err(would_block()),
+        }
+
+        let overlapped = self.inner.read.as_ptr() as *mut _;
+        match self.inner.issue_read(io.apc, &mut *buf, overlapped) {
+            // See `NamedPipe::connect` for the rationale behind `forget`
+            Ok(_) => {
+                io.read = State::DirectPending;
+                io.read_deadline = io.start_deadline(io.read_timeout);
+                mem::forget(self.inner.clone());
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Takes the result of a read issued by [`read_overlapped_into`].
+    ///
+    /// Returns the number of bytes written into the caller's buffer, or a
+    /// "would block" error if the read hasn't completed yet. After this
+    /// returns the pipe resumes its internally buffered reads, unless they
+    /// haven't been started yet, see [`start_reading`].
+    ///
+    /// [`read_overlapped_into`]: NamedPipe::read_overlapped_into
+    /// [`start_reading`]: NamedPipe::start_reading
+    pub fn take_overlapped_read(&self) -> io::Result<usize> {
+        let mut io = self.inner.io.lock().unwrap();
+
+        let res = match mem::replace(&mut io.read, State::None) {
+            State::DirectOk(n) => Ok(n),
+            State::Err(ref e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => {
+                self.inner.eof()
+            }
+            State::Err(e) => Err(e),
+            state => {
+                io.read = state;
+                return Err(would_block());
+            }
+        };
+        Inner::schedule_read(&self.inner, &mut io, None);
+        res
+    }
+
+    /// Issues an overlapped write directly from `buf`, bypassing the internal
+    /// buffering.
+    ///
+    /// The write is only issued if no other write is in flight, otherwise a
+    /// "would block" error is returned. Once the write completes this pipe is
+    /// flagged as writable and [`take_overlapped_write`] returns the number of
+    /// bytes written from `buf`.
+    ///
+    /// [`take_overlapped_write`]: NamedPipe::take_overlapped_write
+    ///
+    /// # Safety
+    ///
+    /// The kernel reads from `buf` *after* this function returns. The caller
+    /// must ensure the memory behind `buf` is neither moved, freed nor
+    /// modified until `take_overlapped_write` returns something other than a
+    /// "would block" error. Writes are not cancelled when the pipe is dropped.
+    pub unsafe fn write_overlapped_from(&self, buf: Pin<&[u8]>) -> io::Result<()> {
+        self.inner.check_access(PipeAccess::Outbound)?;
+        self.inner.check_message_size(buf.len())?;
+        let mut io = self.inner.io.lock().unwrap();
+        io.check_writable()?;
+
+        let overlapped = self.inner.write.as_ptr() as *mut _;
+        match self.inner.issue_write(io.apc, &*buf, overlapped) {
+            // Even if the write completed immediately a completion is posted,
+            // `write_done` takes care of the result either way.
+            Ok(_) => {
+                io.write = State::DirectPending;
+                io.writable_notified = false;
+                io.write_deadline = io.start_deadline(io.write_timeout);
+                mem::forget(self.inner.clone());
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Takes the result of a write issued by [`write_overlapped_from`].
+    ///
+    /// Returns the number of bytes written from the caller's buffer, or a
+    /// "would block" error if the write hasn't completed yet.
+    ///
+    /// [`write_overlapped_from`]: NamedPipe::write_overlapped_from
+    pub fn take_overlapped_write(&self) -> io::Result<usize> {
+        let mut io = self.inner.io.lock().unwrap();
+
+        match mem::replace(&mut io.write, State::None) {
+            State::DirectOk(n) => Ok(n),
+            State::Err(e) => Err(e),
+            state => {
+                io.write = state;
+                Err(would_block())
+            }
+        }
+    }
+}
+
+/// Creates a connected pair of pipes, e.g. for communicating with a child
+/// process.
+///
+/// Pipes created by `CreatePipe` don't support overlapped I/O, so this creates
+/// a named pipe under a unique name instead and connects a client to it right
+/// away, returning `(server, client)`. Both ends are duplex, opened for
+/// overlapped I/O and can be used like any other `NamedPipe`. The pipe
+/// accepts a single instance only, so no other process can connect to it
+/// once this returns, although one racing the client can make this fail
+/// with an error of kind `AddrInUse` or `PermissionDenied`.
+///
+/// Neither handle is inheritable. To pass one of the ends to a child process,
+/// e.g. as its standard input via `STARTUPINFO`, first mark it inheritable
+/// using [`NamedPipe::set_inheritable`].
+pub fn anonymous_pipe() -> io::Result<(NamedPipe, NamedPipe)> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let addr = format!(
+        r"\\.\pipe\mio-anonymous-{}-{}",
+        process::id(),
+        NEXT_ID.fetch_add(1, SeqCst)
+    );
+    let mut builder = NamedPipeBuilder::new(&addr);
+    builder.max_instances = 1;
+    let server = builder.create()?;
+
+    let client = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(FILE_FLAG_OVERLAPPED)
+        .open(&addr)?;
+    Ok((server, NamedPipe::from_handle(client)))
+}
+
+/// Creates both ends of a duplex named pipe session, returning
+/// `(server, client)`.
+///
+/// This is a shorthand for [`anonymous_pipe`] meant for tests and examples.
+/// Both ends are connected, opened for overlapped I/O and ready to be
+/// registered with `Poll`.
+#[cfg(feature = "test-util")]
+pub fn pair() -> io::Result<(NamedPipe, NamedPipe)> {
+    anonymous_pipe()
+}
+
+/// Waits for an overlapped operation of any of `pipes` to complete.
+///
+/// Returns the index of the first pipe with a completed operation, or an error
+/// of kind `TimedOut` if none completed within `timeout`.
+///
+/// This is a lightweight alternative to a full `Poll` for tests and simple
+/// tools. It waits on the events set using
+/// [`NamedPipe::set_overlapped_events`], which the system signals when the
+/// operation they belong to completes, so every pipe needs at least one of
+/// them. A manual-reset event stays signalled until the next operation using
+/// it is issued. At most 64 events can be waited on at once, across all pipes.
+/// Note that the completion still needs to be processed by the `Poll` the pipe
+/// is registered with before reading or writing observes it.
+pub fn wait_any(pipes: &[&NamedPipe], timeout: Option<Duration>) -> io::Result<usize> {
+    // The events of each pipe along with the index of the pipe.
+    let mut handles = Vec::new();
+    let mut owners = Vec::new();
+    for (i, pipe) in pipes.iter().enumerate() {
+        let events = pipe.inner.events.lock().unwrap();
+        let before = handles.len();
+        for event in [&events.connect, &events.read, &events.write].iter() {
+            if let Some(ref event) = **event {
+                handles.push(event.0);
+                owners.push(i);
+            }
+        }
+        if handles.len() == before {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "named pipe has no overlapped events to wait on",
+            ));
+        }
+    }
+    if handles.is_empty() || handles.len() > MAXIMUM_WAIT_OBJECTS as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can only wait for 1 to 64 events",
+        ));
+    }
+
+    let ret = unsafe {
+        WaitForMultipleObjects(
+            handles.len() as DWORD,
+            handles.as_ptr(),
+            FALSE,
+            timeout_ms(timeout),
+        )
+    };
+    match ret {
+        WAIT_FAILED => Err(io::Error::last_os_error()),
+        WAIT_TIMEOUT => Err(timed_out("wait")),
+        n if n < WAIT_OBJECT_0 + handles.len() as DWORD => Ok(owners[(n - WAIT_OBJECT_0) as usize]),
+        n => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("unexpected WaitForMultipleObjects result: {}", n),
+        )),
+    }
+}
+
+/// Converts `timeout` into milliseconds for the wait functions.
+fn timeout_ms(timeout: Option<Duration>) -> DWORD {
+    match timeout {
+        // Round up, so we don't wake up before the timeout elapsed.
+        Some(dur) => cmp::min(
+            dur.as_millis() + u128::from(dur.subsec_nanos() % 1_000_000 != 0),
+            u128::from(INFINITE - 1),
+        ) as DWORD,
+        None => INFINITE,
+    }
+}
+
+/// An `OVERLAPPED` for operations which are waited on synchronously, rather
+/// than through `Poll`.
+///
+/// The low bit of its event handle is set, which keeps the system from queueing
+/// the completion to the completion port the pipe is associated with.
+struct SyncOverlapped {
+    overlapped: OVERLAPPED,
+    event: OwnedEvent,
+}
+
+impl SyncOverlapped {
+    fn new() -> io::Result<SyncOverlapped> {
+        let event = syscall!(
+            CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null()),
+            PartialEq::eq,
+            ptr::null_mut()
+        )?;
+        let event = OwnedEvent(event);
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = (event.0 as usize | 1) as HANDLE;
+        Ok(SyncOverlapped { overlapped, event })
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut OVERLAPPED {
+        &mut self.overlapped
+    }
+
+    /// Waits up to `timeout` for the operation issued on `handle` to complete,
+    /// cancelling it afterwards. Returns the number of bytes transferred.
+    ///
+    /// This always waits for the operation to be done with its buffers, even
+    /// if it's cancelled, so the caller can release them afterwards.
+    fn wait(
+        &mut self,
+        handle: &pipe::NamedPipe,
+        timeout: Option<Duration>,
+        op: &str,
+    ) -> io::Result<usize> {
+        let event = self.event.0;
+        let waited = match unsafe { WaitForSingleObject(event, timeout_ms(timeout)) } {
+            WAIT_OBJECT_0 => Ok(()),
+            WAIT_TIMEOUT => Err(timed_out(op)),
+            _ => Err(io::Error::last_os_error()),
+        };
+        if waited.is_err() {
+            unsafe {
+                CancelIoEx(handle.as_raw_handle() as HANDLE, &mut self.overlapped);
+                WaitForSingleObject(event, INFINITE);
+            }
+        }
+
+        let mut n = 0;
+        let res = syscall!(
+            GetOverlappedResult(
+                handle.as_raw_handle() as HANDLE,
+                &mut self.overlapped,
+                &mut n,
+                FALSE,
+            ),
+            PartialEq::eq,
+            FALSE
+        );
+        match (waited, res) {
+            (_, Ok(_)) => Ok(n as usize),
+            (Err(e), Err(ref err))
+                if err.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) =>
+            {
+                Err(e)
+            }
+            (_, Err(e)) => Err(e),
+        }
+    }
+}
+
+/// Applies a completion dequeued from a completion port shared with other
+/// handles to the [`NamedPipe`] owning `overlapped`.
+///
+/// Use this when driving a completion port directly, rather than through
+/// `Poll`. The owning pipe is found from the `overlapped` pointer alone, the
+/// same way `Poll` does it, so the completion key is irrelevant. `bytes` and
+/// `err` are what the completion port reported for the operation. Note that
+/// the result of the operation itself is taken from `*overlapped`, which the
+/// system updates before queueing the completion, `err` is only used for
+/// consistency checks.
+///
+/// # Safety
+///
+/// `overlapped` must be the pointer of a completion dequeued for an operation
+/// issued by a `NamedPipe`, and every completion must be dispatched exactly
+/// once: the pipe keeps its internal state alive for as long as an operation
+/// is in flight and releases it here. Passing any other pointer, e.g. one of
+/// an operation issued on another handle, is undefined behavior.
+pub unsafe fn dispatch_completion(
+    overlapped: *mut OVERLAPPED,
+    bytes: usize,
+    err: Option<io::Error>,
+) {
+    // `Internal` holds the `NTSTATUS` of the operation, which is only zero on
+    // success.
+    debug_assert_eq!(err.is_some(), (*overlapped).Internal != 0, "{:?}", err);
+    let status = CompletionStatus::new(bytes as u32, 0, overlapped);
+    let callback = (*(overlapped as *mut Overlapped)).callback;
+    callback(status.entry(), None);
+}
+
+/// Completion routine of the reads and writes issued in APC mode, see
+/// `NamedPipe::set_apc_mode`.
+unsafe extern "system" fn apc_done(err: DWORD, bytes: DWORD, overlapped: *mut OVERLAPPED) {
+    let err = match err {
+        0 => None,
+        err => Some(io::Error::from_raw_os_error(err as i32)),
+    };
+    dispatch_completion(overlapped, bytes as usize, err);
+}
+
+/// A builder to create a [`NamedPipe`] server with non-default options.
+///
+/// [`NamedPipe::new`] is the same as `NamedPipeBuilder::new(addr).create()`.
+pub struct NamedPipeBuilder {
+    addr: OsString,
+    access: PipeAccess,
+    // Arguments passed to `CreateNamedPipeW`, `open_mode` excluding `access`.
+    open_mode: DWORD,
+    pipe_mode: DWORD,
+    max_instances: DWORD,
+    out_buffer_size: DWORD,
+    in_buffer_size: DWORD,
+    alloc: Option<Arc<dyn BufferAlloc>>,
+    direct: bool,
+    max_read_ahead: usize,
+    write_full_policy: WriteFullPolicy,
+    completion_key: Option<usize>,
+    max_message_size: Option<usize>,
+    initial_read: bool,
+    inheritable: bool,
+    strict_eof: bool,
+    prewarm_buffers: usize,
+    sub_tokens: bool,
+    connect_retries: u32,
+    timer_affinity: Option<usize>,
+    buffer_alignment: usize,
+    max_read_size: Option<usize>,
+    normalize_addr: bool,
+    max_events_per_poll: Option<usize>,
+}
+
+impl NamedPipeBuilder {
+    /// Creates a new builder for a named pipe at the specified `addr`.
+    ///
+    /// `addr` is either a full path like `\\.\pipe\name`, or just the name
+    /// of the pipe, see [`normalize_addr`].
+    ///
+    /// [`normalize_addr`]: NamedPipeBuilder::normalize_addr
+    pub fn new<A: AsRef<OsStr>>(addr: A) -> NamedPipeBuilder {
+        NamedPipeBuilder {
+            addr: addr.as_ref().to_owned(),
+            access: PipeAccess::Duplex,
+            open_mode: FILE_FLAG_FIRST_PIPE_INSTANCE | FILE_FLAG_OVERLAPPED,
+            pipe_mode: PIPE_TYPE_BYTE,
+            max_instances: PIPE_UNLIMITED_INSTANCES,
+            out_buffer_size: 65536,
+            in_buffer_size: 65536,
+            alloc: None,
+            direct: false,
+            max_read_ahead: usize::max_value(),
+            write_full_policy: WriteFullPolicy::WouldBlock,
+            completion_key: None,
+            max_message_size: None,
+            initial_read: true,
+            inheritable: false,
+            strict_eof: false,
+            prewarm_buffers: 0,
+            sub_tokens: false,
+            connect_retries: 0,
+            timer_affinity: None,
+            buffer_alignment: 1,
+            max_read_size: None,
+            normalize_addr: true,
+            max_events_per_poll: None,
+        }
+    }
+
+    /// Sets the direction(s) in which the named pipe can be used.
+    ///
+    /// For an inbound pipe `write` returns an error and for an outbound pipe
+    /// `read` does, rather than the OS failing with `ERROR_ACCESS_DENIED`.
+    /// Outbound pipes also don't schedule reads in the background. Note that
+    /// the completion of a `connect` is still signalled by a writable
+    /// notification for inbound pipes.
+    ///
+    /// Defaults to [`PipeAccess::Duplex`].
+    pub fn access(&mut self, access: PipeAccess) -> &mut NamedPipeBuilder {
+        self.access = access;
+        self
+    }
+
+    /// Sets `FILE_FLAG_WRITE_THROUGH` when creating the named pipe.
+    ///
+    /// Writes then don't return until the data was transmitted to the client,
+    /// bypassing the buffering normally done by the system. This is only
+    /// effective if the client is on a different computer, at the cost of a
+    /// higher latency for every write.
+    ///
+    /// Defaults to `false`.
+    pub fn write_through(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        set_flag(&mut self.open_mode, FILE_FLAG_WRITE_THROUGH, enabled);
+        self
+    }
+
+    /// Disables the internal buffering of the named pipe.
+    ///
+    /// By default reads and writes go through buffers owned by the pipe, which
+    /// costs a copy and, for reads, an additional completion round-trip before
+    /// data is available. In direct mode no internal reads are scheduled and
+    /// I/O is done with [`NamedPipe::read_overlapped_into`] and
+    /// [`NamedPipe::write_overlapped_from`] against the caller's buffers
+    /// instead, trading throughput for latency on small control messages.
+    /// The `Read` and `Write` implementations return an error in this mode.
+    ///
+    /// The caller's buffers are owned by the kernel while an operation is in
+    /// flight: they must not be moved, freed or accessed until the matching
+    /// `take_overlapped_*` method returns something other than a "would block"
+    /// error. This includes the case where the pipe is dropped, in which
+    /// pending operations complete or are cancelled asynchronously.
+    ///
+    /// Defaults to `false`.
+    pub fn direct_mode(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        self.direct = enabled;
+        self
+    }
+
+    /// Limits the number of bytes read ahead of the application.
+    ///
+    /// The pipe reads into an internal buffer in the background and only
+    /// issues the next read once the application drained that buffer, so the
+    /// unread bytes buffered never exceed the size of a single read. By
+    /// default that size is the capacity of the buffer taken from the pool,
+    /// which can be large if the pool also holds buffers of large writes.
+    /// This caps the size of each read at `limit` bytes, leaving anything
+    /// beyond it in the system's buffer where it exerts backpressure on the
+    /// client.
+    ///
+    /// Defaults to unbounded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn max_read_ahead(&mut self, limit: usize) -> &mut NamedPipeBuilder {
+        assert!(limit > 0, "`max_read_ahead` must be greater than zero");
+        self.max_read_ahead = limit;
+        self
+    }
+
+    /// Creates the named pipe in message mode, rather than byte mode.
+    ///
+    /// Data is then written and read as a stream of messages, with both
+    /// `PIPE_TYPE_MESSAGE` and `PIPE_READMODE_MESSAGE` set.
+    ///
+    /// Defaults to `false`.
+    pub fn message_mode(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        set_flag(
+            &mut self.pipe_mode,
+            PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE,
+            enabled,
+        );
+        self
+    }
+
+    /// Sets the size of the largest message that can be written in message
+    /// mode, see [`message_mode`].
+    ///
+    /// Every write of a message mode pipe sends a single message. Writes
+    /// larger than this limit fail right away with an error of kind
+    /// `InvalidInput`, rather than in obscure ways later on. It has no effect
+    /// in byte mode.
+    ///
+    /// Defaults to the size of the outgoing buffer as reported by
+    /// [`NamedPipe::info`], if it's not zero.
+    ///
+    /// [`message_mode`]: NamedPipeBuilder::message_mode
+    pub fn max_message_size(&mut self, size: usize) -> &mut NamedPipeBuilder {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    /// Sets whether the handle of the named pipe can be inherited by child
+    /// processes, see [`NamedPipe::set_inheritable`].
+    ///
+    /// Defaults to `false`.
+    pub fn inheritable(&mut self, inheritable: bool) -> &mut NamedPipeBuilder {
+        self.inheritable = inheritable;
+        self
+    }
+
+    /// Sets whether reads return an error once the peer disconnected.
+    ///
+    /// By default the end of the stream is signalled by `Ok(0)`, as usual for
+    /// `Read`. In strict mode reads return an error of kind `UnexpectedEof`
+    /// instead, so protocols that never expect empty reads can't mistake a
+    /// disconnect for one, e.g. a zero-length message.
+    ///
+    /// Defaults to `false`.
+    pub fn strict_eof(&mut self, strict: bool) -> &mut NamedPipeBuilder {
+        self.strict_eof = strict;
+        self
+    }
+
+    /// Sets whether a read is scheduled as soon as the named pipe is
+    /// registered (and connected).
+    ///
+    /// Some protocols require the server to write first, e.g. a greeting.
+    /// Without the initial read nothing is read until [`start_reading`] is
+    /// called, until then `read` returns a "would block" error.
+    ///
+    /// Defaults to `true`.
+    ///
+    /// [`start_reading`]: NamedPipe::start_reading
+    pub fn initial_read(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        self.initial_read = enabled;
+        self
+    }
+
+    /// Sets what `write` does while a previous write is still in flight.
+    ///
+    /// Defaults to [`WriteFullPolicy::WouldBlock`].
+    pub fn write_full_policy(&mut self, policy: WriteFullPolicy) -> &mut NamedPipeBuilder {
+        self.write_full_policy = policy;
+        self
+    }
+
+    /// Sets the completion key used when the handle is associated with the
+    /// completion port of a `Poll`.
+    ///
+    /// Mio itself dispatches completions by their `OVERLAPPED` pointer and
+    /// doesn't look at the key, but completion port loops sharing the port
+    /// can use it to tell pipes apart.
+    ///
+    /// Defaults to the address of the pipe's internal state, see
+    /// [`NamedPipe::completion_key`].
+    pub fn completion_key(&mut self, key: usize) -> &mut NamedPipeBuilder {
+        self.completion_key = Some(key);
+        self
+    }
+
+    /// Sets `FILE_FLAG_FIRST_PIPE_INSTANCE` when creating the named pipe.
+    ///
+    /// Creation then fails with an error of kind `AddrInUse` if a pipe with
+    /// the same name already exists, which prevents another process from
+    /// squatting on the name by creating the pipe first. Servers creating
+    /// additional instances of their own pipe must disable this.
+    ///
+    /// Defaults to `true`.
+    pub fn first_instance(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        set_flag(&mut self.open_mode, FILE_FLAG_FIRST_PIPE_INSTANCE, enabled);
+        self
+    }
+
+    /// Sets `PIPE_REJECT_REMOTE_CLIENTS` when creating the named pipe.
+    ///
+    /// Clients on other machines then can't connect to the pipe, only local
+    /// ones can. This works in both byte and message mode. The flag is
+    /// supported since Windows Vista, creating the pipe on older versions
+    /// fails with an error of kind `InvalidInput`.
+    ///
+    /// Defaults to `false`.
+    pub fn reject_remote_clients(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        set_flag(&mut self.pipe_mode, PIPE_REJECT_REMOTE_CLIENTS, enabled);
+        self
+    }
+
+    /// Sets `PIPE_NOWAIT` when creating the named pipe.
+    ///
+    /// This is the same mode [`NamedPipe::set_nowait`] toggles, but it's in
+    /// effect from the start, before the first operation is issued. Only use
+    /// it for interop with processes that depend on it.
+    ///
+    /// Note that overlapped I/O mostly overrides the practical effect of the
+    /// flag: operations still report completion through the completion port,
+    /// they just fail fast instead of staying pending. See `set_nowait` for
+    /// how this interacts with the internal buffering.
+    ///
+    /// Defaults to `false`.
+    pub fn nowait_at_open(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        set_flag(&mut self.pipe_mode, PIPE_NOWAIT, enabled);
+        self
+    }
+
+    /// Uses `pool` for the internal buffers of the named pipe.
+    ///
+    /// By default each named pipe owns a small pool of its own. Servers with
+    /// many short-lived connections can share a single pool between all their
+    /// pipes to amortize the buffer allocations across connections.
+    pub fn buffer_pool(&mut self, pool: Arc<Mutex<BufferPool>>) -> &mut NamedPipeBuilder {
+        self.alloc = Some(pool);
+        self
+    }
+
+    /// Uses `alloc` as the source of the internal buffers of the named pipe.
+    ///
+    /// This is a generalization of [`buffer_pool`] for deployments which want
+    /// to manage the buffer memory themselves, e.g. using a slab allocator.
+    ///
+    /// [`buffer_pool`]: NamedPipeBuilder::buffer_pool
+    pub fn buffer_alloc(&mut self, alloc: Arc<dyn BufferAlloc>) -> &mut NamedPipeBuilder {
+        self.alloc = Some(alloc);
+        self
+    }
+
+    /// Puts `n` newly allocated buffers into the buffer pool when the named
+    /// pipe is created.
+    ///
+    /// The first reads and writes then reuse these buffers instead of
+    /// allocating, trading startup memory for a steadier latency of the first
+    /// request. A pool only holds on to as many buffers as its capacity, see
+    /// [`BufferPool::new`], the pipe's own pool holds 2.
+    ///
+    /// Defaults to 0.
+    pub fn prewarm_buffers(&mut self, n: usize) -> &mut NamedPipeBuilder {
+        self.prewarm_buffers = n;
+        self
+    }
+
+    /// Reports the completions of connects, reads and writes using distinct
+    /// tokens.
+    ///
+    /// By default all events of the pipe carry the token it's registered
+    /// with. With sub-tokens the registered token is a base: connects are
+    /// reported with the base token, reads with the base plus one and writes
+    /// with the base plus two. Event loops can then dispatch to the right
+    /// handler without inspecting the pipe. The pipe consumes these three
+    /// tokens, so the next pipe's base token must be at least three higher.
+    /// Registering with a token leaving no room for the offsets fails with an
+    /// error of kind `InvalidInput`.
+    ///
+    /// Defaults to `false`.
+    pub fn sub_tokens(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        self.sub_tokens = enabled;
+        self
+    }
+
+    /// Limits how many readiness events the named pipe contributes to a
+    /// single poll.
+    ///
+    /// A busy pipe can produce several events while the completions of one
+    /// `Poll::poll` are processed. With many pipes served by one event loop
+    /// this keeps a single pipe from filling up the events and starving the
+    /// others. Once the pipe contributed `limit` events to a poll, further
+    /// notifications are deferred to a later poll rather than dropped. Events
+    /// of all [`sub_tokens`] count towards the same limit.
+    ///
+    /// Defaults to unbounded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    ///
+    /// [`sub_tokens`]: NamedPipeBuilder::sub_tokens
+    pub fn max_events_per_poll(&mut self, limit: usize) -> &mut NamedPipeBuilder {
+        assert!(limit > 0, "`max_events_per_poll` must be greater than zero");
+        self.max_events_per_poll = Some(limit);
+        self
+    }
+
+    /// Sets how often [`NamedPipe::connect`] retries a connect failing with
+    /// a transient error before returning it.
+    ///
+    /// In tight connect/disconnect loops `ConnectNamedPipe` can fail with
+    /// `ERROR_NO_DATA`, if a client connected and closed its end before the
+    /// server disconnected the previous session, or `ERROR_PIPE_LISTENING`.
+    /// Only these are considered transient. Before each retry the pipe is
+    /// disconnected, the retry is then issued in the background after a delay
+    /// starting at 1ms and doubling every attempt. Meanwhile `connect`
+    /// returns a "would block" error, like for a connect that doesn't
+    /// complete immediately, and the outcome is reported the same way.
+    ///
+    /// Defaults to 0.
+    pub fn connect_retries(&mut self, retries: u32) -> &mut NamedPipeBuilder {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// Sets the processor affinity mask of the thread enforcing the read and
+    /// write timeouts, see [`NamedPipe::set_read_timeout`].
+    ///
+    /// The mask is applied using `SetThreadAffinityMask` when the thread is
+    /// spawned. If that fails the thread exits right away and setting the
+    /// timeout fails, leaving the previous timeout in place.
+    ///
+    /// Defaults to the affinity inherited from the process.
+    pub fn timer_affinity(&mut self, mask: usize) -> &mut NamedPipeBuilder {
+        self.timer_affinity = Some(mask);
+        self
+    }
+
+    /// Aligns the start of the data transferred by each internal read and
+    /// write to `align` bytes, e.g. the sector size for transfers which
+    /// require it.
+    ///
+    /// The buffers remain plain `Vec<u8>`s, so this is done by allocating
+    /// `align - 1` extra bytes and using the buffer from its first aligned
+    /// address. The offset is computed whenever a buffer is taken from the
+    /// pool, so recycled buffers are aligned as well. Note that the rest of a
+    /// partially completed write is continued at an unaligned address, and
+    /// [direct mode] uses the caller's buffers as is.
+    ///
+    /// Defaults to 1, i.e. the natural alignment of `Vec<u8>`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    ///
+    /// [direct mode]: NamedPipeBuilder::direct_mode
+    pub fn buffer_alignment(&mut self, align: usize) -> &mut NamedPipeBuilder {
+        assert!(
+            align.is_power_of_two(),
+            "`buffer_alignment` must be a power of two"
+        );
+        self.buffer_alignment = align;
+        self
+    }
+
+    /// Adapts the size of the internal read buffers to the traffic, growing
+    /// them up to `max` bytes.
+    ///
+    /// Whenever a read fills its whole buffer, indicating a fast stream, the
+    /// size of the next buffers is doubled. Whenever a read returns less than
+    /// a quarter of it the size is halved again, down to the default of 4KiB.
+    /// This trades memory for fewer reads on busy pipes, without having to
+    /// pick a fixed size. Note that larger buffers taken from the pool are
+    /// used as is, see [`buffer_pool`].
+    ///
+    /// Defaults to disabled, i.e. buffers of a fixed size.
+    ///
+    /// [`buffer_pool`]: NamedPipeBuilder::buffer_pool
+    pub fn adaptive_read_size(&mut self, max: usize) -> &mut NamedPipeBuilder {
+        self.max_read_size = Some(max);
+        self
+    }
+
+    /// Prepends `\\.\pipe\` to addresses which are just the name of the
+    /// pipe, i.e. don't start with a backslash.
+    ///
+    /// Full paths, starting with `\\`, are used as is. Addresses starting
+    /// with a single backslash, empty ones and ones longer than the 256
+    /// characters Windows allows make creating the pipe fail with an error of
+    /// kind `InvalidInput`. Disabling this passes the address to
+    /// `CreateNamedPipeW` unchanged.
+    ///
+    /// Defaults to `true`.
+    pub fn normalize_addr(&mut self, enabled: bool) -> &mut NamedPipeBuilder {
+        self.normalize_addr = enabled;
+        self
+    }
+
+    /// Creates the named pipe with the configured options.
+    pub fn create(&mut self) -> io::Result<NamedPipe> {
+        let addr = if self.normalize_addr {
+            pipe_addr(&self.addr)?
+        } else {
+            self.addr.clone()
+        };
+        let name: Vec<u16> = addr.encode_wide().chain(Some(0)).collect();
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: ptr::null_mut(),
+            bInheritHandle: self.inheritable as i32,
+        };
+        let handle = syscall!(
+            CreateNamedPipeW(
+                name.as_ptr(),
+                self.open_mode | self.access.open_mode(),
+                self.pipe_mode,
+                self.max_instances,
+                self.out_buffer_size,
+                self.in_buffer_size,
+                0,
+                &mut attributes,
+            ),
+            PartialEq::eq,
+            INVALID_HANDLE_VALUE
+        )
+        .map_err(|e| self.map_create_error(&addr, e))?;
+        // Safety: nothing actually unsafe about this. The trait fn includes
+        // `unsafe`.
+        let mut pipe = unsafe { NamedPipe::from_raw_handle(handle as RawHandle) };
+        let max_message_size = if self.pipe_mode & PIPE_TYPE_MESSAGE == 0 {
+            None
+        } else if self.max_message_size.is_some() {
+            self.max_message_size
+        } else {
+            match pipe.info()?.out_buffer_size {
+                0 => None,
+                size => Some(size as usize),
+            }
+        };
+        // The pipe was just created so nothing else references `inner` yet.
+        let inner = Arc::get_mut(&mut pipe.inner).unwrap();
+        if let Some(alloc) = &self.alloc {
+            inner.alloc = alloc.clone();
+        }
+        inner.buffer_alignment = self.buffer_alignment;
+        inner.max_read_size = self.max_read_size;
+        inner.max_events_per_poll = self.max_events_per_poll;
+        for _ in 0..self.prewarm_buffers {
+            let cap = DEFAULT_BUFFER_SIZE + inner.buffer_alignment - 1;
+            inner.alloc.put(Vec::with_capacity(cap));
+        }
+        inner.access = self.access;
+        inner.direct = self.direct;
+        inner.max_read_ahead = self.max_read_ahead;
+        inner.write_full_policy = self.write_full_policy;
+        inner.completion_key = self.completion_key;
+        inner.max_message_size = max_message_size;
+        inner.strict_eof = self.strict_eof;
+        inner.sub_tokens = self.sub_tokens;
+        inner.connect_retries = self.connect_retries;
+        inner.timer_affinity = self.timer_affinity;
+        inner.io.get_mut().unwrap().reading_deferred = !self.initial_read;
+        inner.addr = Some(addr);
+        Ok(pipe)
+    }
+
+    /// Turns the errors returned when the pipe exists already while
+    /// `first_instance` is set into something more descriptive.
+    fn map_create_error(&self, addr: &OsStr, err: io::Error) -> io::Error {
+        if self.open_mode & FILE_FLAG_FIRST_PIPE_INSTANCE == 0 {
+            return err;
+        }
+        match err.raw_os_error().map(|code| code as DWORD) {
+            Some(ERROR_ACCESS_DENIED) | Some(ERROR_PIPE_BUSY) => io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("named pipe {:?} already exists", addr),
+            ),
+            _ => err,
+        }
+    }
+}
+
+/// Returns the full path of the named pipe at `addr`, see
+/// `NamedPipeBuilder::normalize_addr`.
+fn pipe_addr(addr: &OsStr) -> io::Result<OsString> {
+    const BACKSLASH: u16 = b'\\' as u16;
+
+    let wide: Vec<u16> = addr.encode_wide().collect();
+    let msg = if wide.is_empty() {
+        "named pipe address is empty"
+    } else if wide.starts_with(&[BACKSLASH, BACKSLASH]) {
+        if wide.len() > 256 {
+            "named pipe address is longer than 256 characters"
+        } else {
+            return Ok(addr.to_owned());
+        }
+    } else if wide[0] == BACKSLASH {
+        "named pipe address must start with `\\\\` or be a name"
+    } else {
+        let mut full = OsString::from(r"\\.\pipe\");
+        full.push(addr);
+        if full.encode_wide().count() > 256 {
+            "named pipe address is longer than 256 characters"
+        } else {
+            return Ok(full);
+        }
+    };
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+fn set_flag(mode: &mut DWORD, flag: DWORD, enabled: bool) {
+    if enabled {
+        *mode |= flag;
+    } else {
+        *mode &= !flag;
+    }
+}
+
+impl fmt::Debug for NamedPipeBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedPipeBuilder")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl FromRawHandle for NamedPipe {
+    unsafe fn from_raw_handle(handle: RawHandle) -> NamedPipe {
+        NamedPipe {
+            inner: Arc::new(Inner {
+                // Safety: not really unsafe
+                handle: pipe::NamedPipe::from_raw_handle(handle),
+                connect: Overlapped::new(connect_done),
+                connecting: AtomicBool::new(false),
+                read: Overlapped::new(read_done),
+                write: Overlapped::new(write_done),
+                event: Overlapped::new(event_done),
+                io: Mutex::new(Io {
+                    token: None,
+                    selector: None,
+                    posted: 0,
+                    read: State::None,
+                    write: State::None,
+                    connect_error: None,
+                    connected: false,
+                    writable_notified: false,
+                    read_timeout: None,
+                    write_timeout: None,
+                    read_deadline: None,
+                    write_deadline: None,
+                    read_timed_out: false,
+                    write_timed_out: false,
+                    connect_deadline: None,
+                    connect_timed_out: false,
+                    connect_retry: None,
+                    timer: None,
+                    eof_error: None,
+                    mid_message: false,
+                    eof_truncated: false,
+                    level_triggered: false,
+                    discard_read: false,
+                    discard_write: false,
+                    retract_write: false,
+                    last_read_len: 0,
+                    last_write_len: 0,
+                    last_write_sync: false,
+                    interest: None,
+                    read_cancelled: false,
+                    read_refreshed: false,
+                    would_block_count: 0,
+                    blocking: false,
+                    reading_deferred: false,
+                    apc: false,
+                    foreign_port: false,
+                    last_os_error: None,
+                    read_size: DEFAULT_BUFFER_SIZE,
+                    lifecycle_hook: None,
+                }),
+                alloc: Arc::new(Mutex::new(BufferPool::new(2))),
+                access: PipeAccess::Duplex,
+                direct: false,
+                max_read_ahead: usize::max_value(),
+                write_full_policy: WriteFullPolicy::WouldBlock,
+                write_cond: Condvar::new(),
+                read_cond: Condvar::new(),
+                connect_cond: Condvar::new(),
+                addr: None,
+                events: Mutex::new(OverlappedEvents::default()),
+                completion_key: None,
+                max_message_size: None,
+                strict_eof: false,
+                connect_waker: Mutex::new(None),
+                sub_tokens: false,
+                connect_retries: 0,
+                timer_affinity: None,
+                buffer_alignment: 1,
+                max_read_size: None,
+                max_events_per_poll: None,
+                bytes_read: AtomicU64::new(0),
+                bytes_written: AtomicU64::new(0),
+                owners: AtomicUsize::new(1),
+            }),
+        }
+    }
+}
+
+impl Read for NamedPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        <&NamedPipe as Read>::read(&mut &*self, buf)
+    }
+}
+
+impl Write for NamedPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        <&NamedPipe as Write>::write(&mut &*self, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        <&NamedPipe as Write>::write_vectored(&mut &*self, bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        <&NamedPipe as Write>::flush(&mut &*self)
+    }
+}
+
+impl<'a> Read for &'a NamedPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Inner::read(&self.inner, buf)
+    }
+}
+
+impl<'a> Write for &'a NamedPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        Inner::write_vectored(&self.inner, bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Source for NamedPipe {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+
+        io.check_association(registry, false)?;
+
+        if io.token.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "I/O source already registered with `Registry`",
+            ));
+        }
+        io.check_registrable()?;
+        self.inner.check_token(token)?;
+
+        // A handle stays associated with its completion port until it's
+        // closed, so this is only done once.
+        if io.selector.is_none() {
+            let selector = &poll::selector(registry).inner;
+            let key = self.inner.completion_key();
+            selector.port().add_handle(key, &self.inner.handle)?;
+            io.selector = Some(selector.clone());
+        }
+
+        io.token = Some(token);
+        // Recorded before `post_register`, which only schedules a read for a
+        // readable interest.
+        io.interest = Some(interests);
+        drop(io);
+
+        Inner::post_register(&self.inner, None);
+
+        Ok(())
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+
+        io.check_association(registry, true)?;
+
+        io.check_registrable()?;
+        self.inner.check_token(token)?;
+
+        io.token = Some(token);
+        // Stop reading in the background if the readable interest was
+        // removed, `post_register` starts again once it's added back.
+        let was_readable = io.interest.map_or(true, |i| i.is_readable());
+        io.interest = Some(interests);
+        if was_readable && !interests.is_readable() {
+            if let State::Pending(..) = io.read {
+                // If the read completed in the meantime `read_done` keeps the
+                // data around as usual.
+                io.read_cancelled = true;
+                drop(unsafe { cancel(&self.inner.handle, &self.inner.read) });
+            }
+        }
+        drop(io);
+
+        Inner::post_register(&self.inner, None);
+
+        Ok(())
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        let mut io = self.inner.io.lock().unwrap();
+
+        io.check_association(registry, true)?;
+
+        if io.token.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "I/O source not registered with `Registry`",
+            ));
+        }
+
+        io.token = None;
+        // Cancel the operations in flight so an unregistered pipe has none
+        // outstanding. Their completions are still processed by the `Poll` the
+        // pipe was registered with, releasing the references to `inner` they
+        // hold. Writes are left to flush, like on drop.
+        if let State::Pending(..) = io.read {
+            io.read_cancelled = true;
+            drop(unsafe { cancel(&self.inner.handle, &self.inner.read) });
+        }
+        if self.inner.connecting.load(SeqCst) {
+            drop(unsafe { cancel(&self.inner.handle, &self.inner.connect) });
+        }
+        Ok(())
+    }
+}
+
+impl AsRawHandle for NamedPipe {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.handle.as_raw_handle()
+    }
+}
+
+impl fmt::Debug for NamedPipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.handle.fmt(f)
+    }
+}
+
+/// Two named pipes are equal if they are the same `NamedPipe` value, i.e.
+/// share their internal state and handle.
+///
+/// This is identity, not whether both refer to the same kernel object: a
+/// `NamedPipe` created from a duplicate of another pipe's handle compares
+/// unequal to it, even though both access the same pipe instance.
+impl PartialEq for NamedPipe {
+    fn eq(&self, other: &NamedPipe) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for NamedPipe {}
+
+impl Drop for NamedPipe {
+    fn drop(&mut self) {
+        self.inner.release();
+    }
+}
+
+impl Inner {
+    /// Implementation of `Read` for `NamedPipe` and `OwnedReadHalf`.
+    fn read(me: &Arc<Inner>, buf: &mut [u8]) -> io::Result<usize> {
+        me.check_access(PipeAccess::Inbound)?;
+        me.check_buffered("read_overlapped_into")?;
+        let mut state = me.io.lock().unwrap();
+
+        if state.blocking {
+            let timeout = state.read_timeout;
+            drop(state);
+            return me.read_sync(buf, timeout);
+        }
+
+        if !state.is_active() {
+            return Err(not_registered());
+        }
+
+        match mem::replace(&mut state.read, State::None) {
+            // In theory not possible with `token` checked above,
+            // but return would block for now.
+            State::None => Err(would_block()),
+
+            // A read is in flight, still waiting for it to finish
+            State::Pending(buf, amt) => {
+                state.read = State::Pending(buf, amt);
+                state.would_block_count += 1;
+                Err(would_block())
+            }
+
+            // A read into a caller-provided buffer is in flight or hasn't
+            // been taken yet, see `take_overlapped_read`.
+            s @ State::DirectPending | s @ State::DirectOk(_) => {
+                state.read = s;
+                Err(would_block())
+            }
+
+            // We previously read something into `data`, try to copy out some
+            // data. If we copy out all the data schedule a new read and
+            // otherwise store the buffer to get read later. Note that `state`
+            // stays locked, so concurrent readers get disjoint ranges.
+            State::Ok(data, cur) => {
+                let n = {
+                    let mut remaining = &data[cur..];
+                    remaining.read(buf)?
+                };
+                let next = cur + n;
+                if next != data.len() {
+                    state.read = State::Ok(data, next);
+                    if state.level_triggered {
+                        Inner::notify(&me, &mut state, Op::Read, None);
+                    }
+                } else {
+                    me.put_buffer(data);
+                    Inner::schedule_read(&me, &mut state, None);
+                }
+                Ok(n)
+            }
+
+            // Looks like an in-flight read hit an error, return that here while
+            // we schedule a new one.
+            State::Err(e) => {
+                Inner::schedule_read(&me, &mut state, None);
+                if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
+                    // Remember why the stream ended, see `take_read_error`.
+                    state.eof_truncated = state.mid_message;
+                    state.eof_error = Some(e);
+                    me.eof()
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Appends the data of the last read to `out`, returning whether it ended
+    /// a message or `None` at the end of the stream. See `Messages`.
+    fn read_message_part(me: &Arc<Inner>, out: &mut Vec<u8>) -> io::Result<Option<bool>> {
+        me.check_access(PipeAccess::Inbound)?;
+        me.check_buffered("read_overlapped_into")?;
+        let mut state = me.io.lock().unwrap();
+
+        if !state.is_active() {
+            return Err(not_registered());
+        }
+
+        match mem::replace(&mut state.read, State::None) {
+            // `mid_message` still belongs to `data` as the next read is only
+            // scheduled once `data` is consumed.
+            State::Ok(data, cur) => {
+                out.extend_from_slice(&data[cur..]);
+                let end = !state.mid_message;
+                me.put_buffer(data);
+                Inner::schedule_read(&me, &mut state, None);
+                Ok(Some(end))
+            }
+            State::Err(e) => {
+                Inner::schedule_read(&me, &mut state, None);
+                if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
+                    state.eof_truncated = state.mid_message;
+                    state.eof_error = Some(e);
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+            s => {
+                state.read = s;
+                state.would_block_count += 1;
+                Err(would_block())
+            }
+        }
+    }
+
+    /// Implementation of `Write` for `NamedPipe` and `OwnedWriteHalf`.
+    fn write_vectored(me: &Arc<Inner>, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        me.check_access(PipeAccess::Outbound)?;
+        me.check_buffered("write_overlapped_from")?;
+        me.check_message_size(bufs.iter().map(|b| b.len()).sum())?;
+        let io = me.io.lock().unwrap();
+        if io.blocking {
+            let timeout = io.write_timeout;
+            drop(io);
+            // Like the default `write_vectored`, only the first non-empty
+            // buffer is written.
+            let buf = bufs
+                .iter()
+                .find(|b| !b.is_empty())
+                .map_or(&[][..], |b| &**b);
+            return me.write_sync(buf, timeout);
+        }
+        // Make sure there's no writes pending
+        let mut io = me.wait_writable(io)?;
+
+        // Move `bufs` onto the heap and fire off the write
+        let (owned_buf, pos) = me.buffer_from(bufs);
+        let len = owned_buf.len() - pos;
+        let res = Inner::maybe_schedule_write(&me, owned_buf, pos, &mut io)?;
+        io.last_write_sync = res.is_some();
+        match res {
+            // Some bytes are written immediately
+            Some(n) => Ok(n),
+            // Write operation is anqueued for whole buffer
+            None => Ok(len),
+        }
+    }
+
+    /// Issues an overlapped read, completed through the completion port or,
+    /// in APC mode, by `apc_done`.
+    unsafe fn issue_read(
+        &self,
+        apc: bool,
+        buf: &mut [u8],
+        overlapped: *mut OVERLAPPED,
+    ) -> io::Result<Option<usize>> {
+        if !apc {
+            return self.handle.read_overlapped(buf, overlapped);
+        }
+        let len = cmp::min(buf.len(), DWORD::max_value() as usize) as DWORD;
+        syscall!(
+            ReadFileEx(
+                self.handle.as_raw_handle() as HANDLE,
+                buf.as_mut_ptr() as *mut _,
+                len,
+                overlapped,
+                Some(apc_done),
+            ),
+            PartialEq::eq,
+            FALSE
+        )?;
+        // The completion routine runs even if the read completed right away.
+        Ok(None)
+    }
+
+    /// Write counterpart of `issue_read`.
+    unsafe fn issue_write(
+        &self,
+        apc: bool,
+        buf: &[u8],
+        overlapped: *mut OVERLAPPED,
+    ) -> io::Result<Option<usize>> {
+        if !apc {
+            return self.handle.write_overlapped(buf, overlapped);
+        }
+        let len = cmp::min(buf.len(), DWORD::max_value() as usize) as DWORD;
+        syscall!(
+            WriteFileEx(
+                self.handle.as_raw_handle() as HANDLE,
+                buf.as_ptr() as *const _,
+                len,
+                overlapped,
+                Some(apc_done),
+            ),
+            PartialEq::eq,
+            FALSE
+        )?;
+        Ok(None)
+    }
+
+    /// Reads into `buf` synchronously, see `NamedPipe::read_blocking`.
+    fn read_sync(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let mut overlapped = SyncOverlapped::new()?;
+        let res = unsafe {
+            self.handle
+                .read_overlapped(buf, overlapped.as_mut_ptr())
+                .and_then(|_| overlapped.wait(&self.handle, timeout, "read"))
+        };
+        if let Ok(n) = res {
+            self.bytes_read.fetch_add(n as u64, Relaxed);
+        }
+        match res {
+            Err(ref e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => self.eof(),
+            res => res,
+        }
+    }
+
+    /// Returns the result of a read at the end of the stream, see
+    /// `NamedPipeBuilder::strict_eof`.
+    fn eof(&self) -> io::Result<usize> {
+        if self.strict_eof {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "named pipe peer disconnected",
+            ))
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Waits for a client to connect synchronously, see
+    /// `NamedPipe::accept_and_read`.
+    fn connect_sync(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut overlapped = SyncOverlapped::new()?;
+        let connected = unsafe { self.handle.connect_overlapped(overlapped.as_mut_ptr())? };
+        if !connected {
+            overlapped.wait(&self.handle, timeout, "connect")?;
+        }
+        self.io.lock().unwrap().connected = true;
+        Ok(())
+    }
+
+    /// Writes `buf` synchronously, see `NamedPipe::write_blocking`.
+    fn write_sync(&self, buf: &[u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let mut overlapped = SyncOverlapped::new()?;
+        let res = unsafe {
+            self.handle
+                .write_overlapped(buf, overlapped.as_mut_ptr())
+                .and_then(|_| overlapped.wait(&self.handle, timeout, "write"))
+        };
+        if let Ok(n) = res {
+            self.bytes_written.fetch_add(n as u64, Relaxed);
+        }
+        res
+    }
+
+    /// Returns an error if the pipe is registered or any operation is in
+    /// flight.
+    fn check_idle(&self, io: &Io) -> io::Result<()> {
+        let idle = match (&io.read, &io.write) {
+            (State::None, State::None) => !self.connecting.load(SeqCst),
+            _ => false,
+        };
+        if io.token.is_some() || io.foreign_port || !idle {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "named pipe is registered or has operations in flight",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks whether a new write can be issued, handling a write still in
+    /// flight according to the `WriteFullPolicy`.
+    fn wait_writable<'a>(&self, mut io: MutexGuard<'a, Io>) -> io::Result<MutexGuard<'a, Io>> {
+        let mut deadline = None;
+        loop {
+            match io.check_writable() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res.map(|()| io),
+            }
+            let deadline = match self.write_full_policy {
+                WriteFullPolicy::WouldBlock => return Err(would_block()),
+                WriteFullPolicy::WriteZero => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "named pipe write still in flight",
+                    ))
+                }
+                WriteFullPolicy::Block(timeout) => {
+                    *deadline.get_or_insert_with(|| Instant::now() + timeout)
+                }
+            };
+            let now = Instant::now();
+            if deadline <= now {
+                return Err(timed_out("write"));
+            }
+            io = self.write_cond.wait_timeout(io, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Gives up the ownership of a `NamedPipe`, `OwnedReadHalf` or
+    /// `OwnedWriteHalf` when it's dropped.
+    fn release(&self) {
+        // Other values share the pipe, see `NamedPipe::downgrade`.
+        if self.owners.fetch_sub(1, SeqCst) != 1 {
+            return;
+        }
+
+        // Cancel pending reads/connects, but don't cancel writes to ensure that
+        // everything is flushed out.
+        unsafe {
+            if self.connecting.load(SeqCst) {
+                drop(cancel(&self.handle, &self.connect));
+            }
+        }
+        self.cancel_read();
+    }
+
+    /// Cancels the read in flight, if any.
+    fn cancel_read(&self) {
+        let io = self.io.lock().unwrap();
+        match io.read {
+            State::Pending(..) | State::DirectPending => unsafe {
+                drop(cancel(&self.handle, &self.read));
+            },
+            _ => {}
+        }
+    }
+
+    /// Returns an error if data can't flow in `direction`, i.e. from the client
+    /// to the server for `Inbound` or vice versa for `Outbound`.
+    fn check_access(&self, direction: PipeAccess) -> io::Result<()> {
+        if self.access == PipeAccess::Duplex || self.access == direction {
+            return Ok(());
+        }
+        let msg = match self.access {
+            PipeAccess::Inbound => "named pipe is inbound only, it can't be written to",
+            _ => "named pipe is outbound only, it can't be read from",
+        };
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+    }
+
+    /// Returns an error if `token` leaves no room for the offsets of
+    /// `NamedPipeBuilder::sub_tokens`.
+    fn check_token(&self, token: Token) -> io::Result<()> {
+        if self.sub_tokens && token.0.checked_add(2).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "token leaves no room for the sub-tokens of the named pipe",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns `events` unless the pipe already contributed the maximum number
+    /// of events to it, see `NamedPipeBuilder::max_events_per_poll`. The
+    /// notification is then deferred to a later poll.
+    fn limit_events<'a>(
+        &self,
+        io: &Io,
+        events: Option<&'a mut Vec<Event>>,
+    ) -> Option<&'a mut Vec<Event>> {
+        let (limit, base) = match (self.max_events_per_poll, io.token) {
+            (Some(limit), Some(token)) => (limit, token.0),
+            _ => return events,
+        };
+        let span = if self.sub_tokens { 3 } else { 1 };
+        let events = events?;
+        let contributed = events
+            .iter()
+            .filter(|e| event::token(e).0.wrapping_sub(base) < span)
+            .count();
+        if contributed < limit {
+            Some(events)
+        } else {
+            None
+        }
+    }
+
+    /// Returns an error if a message of `len` bytes exceeds the limit set by
+    /// `NamedPipeBuilder::max_message_size`.
+    fn check_message_size(&self, len: usize) -> io::Result<()> {
+        match self.max_message_size {
+            Some(max) if len > max => {
+                let msg = format!(
+                    "message of {} bytes exceeds the maximum message size of {} bytes",
+                    len, max
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns an error if the pipe is in direct mode, in which case `method`
+    /// has to be used instead of the buffered I/O.
+    fn check_buffered(&self, method: &str) -> io::Result<()> {
+        if !self.direct {
+            return Ok(());
+        }
+        let msg = format!("named pipe is in direct mode, use `{}` instead", method);
+        Err(io::Error::new(io::ErrorKind::Other, msg))
+    }
+
+    /// Converts a pointer to `Inner.connect` to a pointer to `Inner`.
+    ///
+    /// # Unsafety
+    ///
+    /// Caller must ensure `ptr` is pointing to `Inner.connect`.
+    unsafe fn ptr_from_conn_overlapped(ptr: *mut OVERLAPPED) -> *const Inner {
+        // `connect` is the first field, so the pointer are the same.
+        ptr.cast()
+    }
+
+    /// Same as [`ptr_from_conn_overlapped`] but for `Inner.read`.
+    unsafe fn ptr_from_read_overlapped(ptr: *mut OVERLAPPED) -> *const Inner {
+        // `read` is after `connect: Overlapped`.
+        (ptr as *mut Overlapped).wrapping_sub(1) as *const Inner
+    }
+
+    /// Same as [`ptr_from_conn_overlapped`] but for `Inner.write`.
+    unsafe fn ptr_from_write_overlapped(ptr: *mut OVERLAPPED) -> *const Inner {
+        // `write` is after `connect: Overlapped` and `read: Overlapped`.
+        (ptr as *mut Overlapped).wrapping_sub(2) as *const Inner
+    }
+
+    /// Same as [`ptr_from_conn_overlapped`] but for `Inner.event`.
+    unsafe fn ptr_from_event_overlapped(ptr: *mut OVERLAPPED) -> *const Inner {
+        // `event` is after the `connect`, `read` and `write` overlapped.
+        (ptr as *mut Overlapped).wrapping_sub(3) as *const Inner
+    }
+
+    /// Returns the key the handle is associated with the completion port
+    /// under, see `NamedPipe::completion_key`.
+    fn completion_key(&self) -> usize {
+        self.completion_key.unwrap_or(self as *const Inner as usize)
+    }
+
+    /// Schedules a read to happen in the background, executing an overlapped
+    /// operation.
+    ///
+    /// This function returns `true` if a normal error happens or if the read
+    /// is scheduled in the background. If the pipe is no longer connected
+    /// (ERROR_PIPE_LISTENING) then `false` is returned and no read is
+    /// scheduled.
+    fn schedule_read(me: &Arc<Inner>, io: &mut Io, events: Option<&mut Vec<Event>>) -> bool {
+        // Outbound pipes can't be read from and in direct mode the caller
+        // issues the reads.
+        if me.access == PipeAccess::Outbound || me.direct {
+            return true;
+        }
+        if io.interest.map_or(false, |i| !i.is_readable()) || io.reading_deferred {
+            return true;
+        }
+
+        // Check to see if a read is already scheduled/completed
+        match io.read {
+            State::None => {}
+            _ => return true,
+        }
+
+        // Allocate a buffer and schedule the read.
+        let mut buf = me.get_buffer_at_least(io.read_size);
+        let offset = me.align_offset(&buf);
+        let e = unsafe {
+            let overlapped = me.read.as_ptr() as *mut _;
+            let len = cmp::min(buf.capacity() - offset, me.max_read_ahead);
+            let slice = slice::from_raw_parts_mut(buf.as_mut_ptr().add(offset), len);
+            me.issue_read(io.apc, slice, overlapped)
+        };
+
+        match e {
+            // See `NamedPipe::connect` above for the rationale behind `forget`
+            Ok(_) => {
+                // The data starts at `offset`, see `read_done`.
+                io.read = State::Pending(buf, offset);
+                io.read_deadline = io.start_deadline(io.read_timeout);
+                mem::forget(me.clone());
+                true
+            }
+
+            // If ERROR_PIPE_LISTENING happens then it's not a real read error,
+            // we just need to wait for a connect.
+            Err(ref e) if e.raw_os_error() == Some(ERROR_PIPE_LISTENING as i32) => false,
+
+            // If some other error happened, though, we're now readable to give
+            // out the error.
+            Err(e) => {
+                io.read = io.failed(e);
+                Inner::notify(me, io, Op::Read, events);
+                true
+            }
+        }
+    }
+
+    /// Maybe schedules overlapped write operation.
+    ///
+    /// * `None` means that overlapped operation was enqueued
+    /// * `Some(n)` means that `n` bytes was immediately written.
+    ///   Note, that `write_done` will fire anyway to clean up the state.
+    fn maybe_schedule_write(
+        me: &Arc<Inner>,
+        buf: Vec<u8>,
+        pos: usize,
+        io: &mut Io,
+    ) -> io::Result<Option<usize>> {
+        // Very similar to `schedule_read` above, just done for the write half.
+        let e = unsafe {
+            let overlapped = me.write.as_ptr() as *mut _;
+            me.issue_write(io.apc, &buf[pos..], overlapped)
+        };
+
+        // See `connect` above for the rationale behind `forget`
+        match e {
+            // `n` bytes are written immediately
+            Ok(Some(n)) => {
+                io.write = State::Ok(buf, pos);
+                io.writable_notified = false;
+                mem::forget(me.clone());
+                Ok(Some(n))
+            }
+            // write operation is enqueued
+            Ok(None) => {
+                io.write = State::Pending(buf, pos);
+                io.writable_notified = false;
+                io.write_deadline = io.start_deadline(io.write_timeout);
+                mem::forget(me.clone());
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Schedules a write of `buf[pos..]`, used to continue a partially
+    /// completed write.
+    ///
+    /// Unlike `maybe_schedule_write` an immediate completion is left for
+    /// `write_done` to process, as nobody is there to observe the result.
+    fn schedule_write(
+        me: &Arc<Inner>,
+        buf: Vec<u8>,
+        pos: usize,
+        io: &mut Io,
+        events: Option<&mut Vec<Event>>,
+    ) {
+        match Inner::maybe_schedule_write(me, buf, pos, io) {
+            Ok(Some(_)) => {
+                // Reinterpret the `Ok` state so that `write_done` advances the
+                // position once the completion status comes in.
+                io.write = match mem::replace(&mut io.write, State::None) {
+                    State::Ok(buf, pos) => State::Pending(buf, pos),
+                    // `io` is locked, so this branch is unreachable
+                    _ => unreachable!(),
+                };
+            }
+            Ok(None) => {}
+            Err(e) => {
+                io.write = io.failed(e);
+                Inner::notify_writable_edge(me, io, Op::Write, events);
+            }
+        }
+    }
+
+    /// Spawns the timer thread if a timeout is set and it isn't running yet.
+    fn start_timer(me: &Arc<Inner>, io: &mut Io) -> io::Result<()> {
+        if io.timer.is_some() || !io.needs_timer() {
+            return Ok(());
+        }
+
+        // The affinity is applied by the thread itself before it starts
+        // cancelling anything, so `io.timer` is only set once it's in place.
+        let weak = Arc::downgrade(me);
+        let affinity = me.timer_affinity;
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("mio-named-pipe-timer".to_owned())
+            .spawn(move || {
+                let res = match affinity {
+                    Some(mask) => syscall!(
+                        SetThreadAffinityMask(GetCurrentThread(), mask),
+                        PartialEq::eq,
+                        0
+                    )
+                    .map(drop),
+                    None => Ok(()),
+                };
+                let started = res.is_ok();
+                drop(tx.send(res));
+                if started {
+                    run_timer(weak);
+                }
+            })?;
+        match rx.recv() {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "named pipe timer thread exited unexpectedly",
+                ))
+            }
+        }
+        io.timer = Some(handle.thread().clone());
+        Ok(())
+    }
+
+    /// Issues the connect of `NamedPipe::connect`, `connecting` must be set.
+    ///
+    /// Returns `Ok(true)` if a client connected immediately and `Ok(false)`
+    /// if the outcome is reported by `connect_done` or, for a transient
+    /// error, the connect is retried by the timer thread later. `connecting`
+    /// is left to the caller on error.
+    fn start_connect(me: &Arc<Inner>, attempt: u32) -> io::Result<bool> {
+        let res = unsafe {
+            let overlapped = me.connect.as_ptr() as *mut _;
+            me.handle.connect_overlapped(overlapped)
+        };
+        match res {
+            // The connection operation finished immediately, so let's schedule
+            // reads/writes and such.
+            Ok(true) => {
+                let mut io = me.io.lock().unwrap();
+                io.connect_deadline = None;
+                io.connected = true;
+                io.writable_notified = false;
+                drop(io);
+                Inner::finish_connect(me, true, None);
+                Ok(true)
+            }
+
+            // If the overlapped operation was successful and didn't finish
+            // immediately then we forget a copy of the arc we hold
+            // internally. This ensures that when the completion status comes
+            // in for the I/O operation finishing it'll have a reference
+            // associated with it and our data will still be valid. The
+            // `connect_done` function will "reify" this forgotten pointer to
+            // drop the refcount on the other side.
+            Ok(false) => {
+                mem::forget(me.clone());
+                Ok(false)
+            }
+
+            // See `NamedPipeBuilder::connect_retries`.
+            Err(ref e) if attempt < me.connect_retries && is_transient(e) => {
+                drop(me.handle.disconnect());
+                let backoff = Duration::from_millis(1 << cmp::min(attempt, 10));
+                let mut io = me.io.lock().unwrap();
+                io.connect_retry = Some((Instant::now() + backoff, attempt + 1));
+                if let Some(timer) = &io.timer {
+                    timer.unpark();
+                } else if let Err(e) = Inner::start_timer(me, &mut io) {
+                    io.connect_retry = None;
+                    io.connect_deadline = None;
+                    return Err(e);
+                }
+                Ok(false)
+            }
+
+            Err(e) => {
+                me.io.lock().unwrap().connect_deadline = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Ends the connecting state once a connect completed, failed or not, and
+    /// reports it.
+    fn finish_connect(me: &Arc<Inner>, connected: bool, events: Option<&mut Vec<Event>>) {
+        // Flag ourselves as no longer using the `connect` overlapped instances.
+        // This is done after storing the error, so `poll_connected` never
+        // reports success for a failed connect.
+        me.connecting.store(false, SeqCst);
+        if let Some(waker) = me.connect_waker.lock().unwrap().take() {
+            waker.wake();
+        }
+        // Waiters check `connecting` with `io` locked, so locking it here makes
+        // sure none of them misses the notification.
+        drop(me.io.lock().unwrap());
+        me.connect_cond.notify_all();
+
+        // We essentially just finished a registration, so kick off a read and
+        // register write readiness.
+        Inner::post_register(me, events);
+
+        if connected {
+            me.fire(PipeEvent::Connected);
+        }
+    }
+
+    fn post_register(me: &Arc<Inner>, mut events: Option<&mut Vec<Event>>) {
+        let mut io = me.io.lock().unwrap();
+        // In level-triggered mode unconsumed data is reported again.
+        if io.level_triggered {
+            if let State::Ok(..) | State::Err(_) = io.read {
+                let events = events.as_mut().map(|ptr| &mut **ptr);
+                Inner::notify(me, &mut io, Op::Read, events);
+            }
+        }
+        // Note that `schedule_read` doesn't reschedule a read already pending.
+        if Inner::schedule_read(&me, &mut io, events.as_mut().map(|ptr| &mut **ptr)) {
+            // Don't notify again if nothing changed since the last writable
+            // notification, e.g. when reregistering to change the token.
+            if let State::None = io.write {
+                Inner::notify_writable_edge(me, &mut io, Op::Connect, events);
+            }
+        }
+    }
+
+    /// Notifies writable readiness, but only on a transition from not writable
+    /// to writable: once after a write completes or a connect finishes.
+    fn notify_writable_edge(me: &Arc<Inner>, io: &mut Io, op: Op, events: Option<&mut Vec<Event>>) {
+        if !io.writable_notified {
+            io.writable_notified = true;
+            Inner::notify(me, io, op, events);
+        }
+    }
+
+    /// Reports the readiness of `op` to the `Poll` the pipe is registered
+    /// with, filtered by the registered interest.
+    ///
+    /// Completion callbacks running inside `Poll::poll` pass the events being
+    /// collected, otherwise the event is posted for the next poll. So are
+    /// events exceeding `NamedPipeBuilder::max_events_per_poll`.
+    fn notify(me: &Arc<Inner>, io: &mut Io, op: Op, events: Option<&mut Vec<Event>>) {
+        let token = match io.token {
+            // `check_token` made sure the offset doesn't overflow.
+            Some(token) if me.sub_tokens => Token(token.0 + op as usize),
+            Some(token) => token,
+            None => return,
+        };
+        let readiness = op.readiness();
+        if let Some(interest) = io.interest {
+            if !Ready::from_interests(interest).contains(readiness) {
+                return;
+            }
+        }
+        match me.limit_events(io, events) {
+            Some(events) => events.push(Event::new(readiness, token)),
+            None => Inner::post(me, io, op),
+        }
+    }
+
+    /// Posts `Inner.event` to the completion port to report the readiness of
+    /// `op` from `event_done`. Readiness posted while the packet is still
+    /// queued is reported along with it.
+    fn post(me: &Arc<Inner>, io: &mut Io, op: Op) {
+        if io.selector.is_none() {
+            return;
+        }
+        let queued = io.posted != 0;
+        io.posted |= op.mask();
+        if queued {
+            return;
+        }
+        let port = io.selector.as_ref().unwrap().port();
+        let status = CompletionStatus::new(0, me.completion_key(), me.event.as_mut_ptr());
+        // See `NamedPipe::connect` above for the rationale behind `forget`
+        mem::forget(me.clone());
+        if port.post(status).is_err() {
+            drop(unsafe { Arc::from_raw(&**me as *const Inner) });
+            io.posted = 0;
+        }
+    }
+
+    /// Adjusts the size of the next read buffers after a read of `n` bytes,
+    /// see `NamedPipeBuilder::adaptive_read_size`.
+    fn adapt_read_size(&self, io: &mut Io, n: usize) {
+        let max = match self.max_read_size {
+            Some(max) => max,
+            None => return,
+        };
+        if n >= io.read_size {
+            io.read_size = cmp::min(io.read_size.saturating_mul(2), max);
+        } else if n < io.read_size / 4 {
+            io.read_size = cmp::max(io.read_size / 2, DEFAULT_BUFFER_SIZE);
+        }
+    }
+
+    /// Calls the hook set by `NamedPipe::set_lifecycle_hook`, if any.
+    ///
+    /// `io` must not be locked by the caller.
+    fn fire(&self, event: PipeEvent) {
+        let hook = self.io.lock().unwrap().lifecycle_hook.clone();
+        if let Some(hook) = hook {
+            hook(event);
+        }
+    }
+
+    /// Copies `bufs` into a single buffer taken from the pool, returning it
+    /// along with the position the data starts at.
+    fn buffer_from(&self, bufs: &[IoSlice<'_>]) -> (Vec<u8>, usize) {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        let mut owned_buf = self.get_buffer_at_least(len);
+        let offset = self.align_offset(&owned_buf);
+        owned_buf.resize(offset, 0);
+        for buf in bufs {
+            owned_buf.extend_from_slice(buf);
+        }
+        (owned_buf, offset)
+    }
+
+    /// Returns the offset of the first address in `buf` aligned as set by
+    /// `NamedPipeBuilder::buffer_alignment`.
+    fn align_offset(&self, buf: &[u8]) -> usize {
+        (buf.as_ptr() as usize).wrapping_neg() & (self.buffer_alignment - 1)
+    }
+
+    /// Takes a buffer from the pool, large enough to be filled with `cap`
+    /// bytes without reallocating.
+    fn get_buffer_at_least(&self, cap: usize) -> Vec<u8> {
+        // Leave room to align the start of the data, see `align_offset`.
+        let cap = cmp::max(cap, DEFAULT_BUFFER_SIZE) + self.buffer_alignment - 1;
+        let mut buf = self.alloc.get(cap);
+        buf.clear();
+        buf
+    }
+
+    fn put_buffer(&self, buf: Vec<u8>) {
+        self.alloc.put(buf)
+    }
+}
+
+fn connect_done(status: &OVERLAPPED_ENTRY, events: Option<&mut Vec<Event>>) {
+    let status = CompletionStatus::from_entry(status);
+
+    // Acquire the `Arc<Inner>`. Note that we should be guaranteed that the
+    // refcount is available to us due to the `mem::forget` in `connect` above.
+    let me = unsafe { Arc::from_raw(Inner::ptr_from_conn_overlapped(status.overlapped())) };
+
+    assert!(
+        me.connecting.load(SeqCst),
+        "NamedPipe was not previously connecting"
+    );
+
+    // Stash away our connect error if one happened
+    debug_assert_eq!(status.bytes_transferred(), 0);
+    let connected = {
+        let mut io = me.io.lock().unwrap();
+        io.connect_deadline = None;
+        let cancelled = mem::replace(&mut io.connect_timed_out, false);
+        let res = match unsafe { me.handle.result(status.overlapped()) } {
+            Err(ref e) if cancelled && e.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) => {
+                Err(timed_out("connect"))
+            }
+            res => res,
+        };
+        // Finishing a connect is a writable transition of its own.
+        io.writable_notified = false;
+        match res {
+            Ok(n) => {
+                debug_assert_eq!(n, 0);
+                io.connected = true;
+                true
+            }
+            Err(e) => {
+                io.connect_error = Some(e);
+                false
+            }
+        }
+    };
+
+    Inner::finish_connect(&me, connected, events);
+}
+
+fn event_done(status: &OVERLAPPED_ENTRY, mut events: Option<&mut Vec<Event>>) {
+    let status = CompletionStatus::from_entry(status);
+
+    // Acquire the `Arc<Inner>` leaked by `Inner::post`.
+    let me = unsafe { Arc::from_raw(Inner::ptr_from_event_overlapped(status.overlapped())) };
+
+    // The token and interest may have changed since the readiness was posted,
+    // `notify` applies the current ones.
+    let mut io = me.io.lock().unwrap();
+    let posted = mem::replace(&mut io.posted, 0);
+    for &op in [Op::Connect, Op::Read, Op::Write].iter() {
+        if posted & op.mask() != 0 {
+            let events = events.as_mut().map(|ptr| &mut **ptr);
+            Inner::notify(&me, &mut io, op, events);
+        }
+    }
+}
+
+fn read_done(status: &OVERLAPPED_ENTRY, events: Option<&mut Vec<Event>>) {
+    let status = CompletionStatus::from_entry(status);
+
+    // Acquire the `Arc<Inner>`. Note that we should be guaranteed that the
+    // refcount is available to us due to the `mem::forget` in `schedule_read`
+    // above.
+    let me = unsafe { Arc::from_raw(Inner::ptr_from_read_overlapped(status.overlapped())) };
+
+    // Move from the `Pending` to `Ok` state.
+    let mut io = me.io.lock().unwrap();
+    io.read_deadline = None;
+    // Waiters only observe the new state once `io` is unlocked.
+    me.read_cond.notify_all();
+    let cancelled = mem::replace(&mut io.read_timed_out, false);
+    let interest_cancelled = mem::replace(&mut io.read_cancelled, false);
+    let refreshed = mem::replace(&mut io.read_refreshed, false);
+    if mem::replace(&mut io.discard_read, false) {
+        // The read belonged to a previous client, see `NamedPipe::recycle`.
+        if let State::Pending(buf, _) = mem::replace(&mut io.read, State::None) {
+            me.put_buffer(buf);
+        }
+        Inner::schedule_read(&me, &mut io, events);
+        return;
+    }
+    io.last_read_len = status.bytes_transferred() as usize;
+    me.bytes_read
+        .fetch_add(u64::from(status.bytes_transferred()), Relaxed);
+    let res = unsafe { me.handle.result(status.overlapped()) };
+    if let Err(ref e) = res {
+        let aborted = e.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32);
+        if (interest_cancelled || refreshed) && aborted {
+            // Nothing was read, see `reregister`, `deregister` and
+            // `NamedPipe::refresh_read`.
+            if let State::Pending(buf, _) = mem::replace(&mut io.read, State::None) {
+                me.put_buffer(buf);
+            }
+            if refreshed && !interest_cancelled {
+                Inner::schedule_read(&me, &mut io, events);
+            }
+            return;
+        }
+    }
+    let res = match res {
+        // Only report a time out if the read was actually aborted, the peer
+        // might have gone away in the meantime and that end-of-file must
+        // still make the pipe readable.
+        Err(ref e) if cancelled && e.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) => {
+            Err(timed_out("read"))
+        }
+        res => res,
+    };
+    io.mid_message = match res {
+        Err(ref e) => e.raw_os_error() == Some(ERROR_MORE_DATA as i32),
+        Ok(_) => false,
+    };
+    // The buffer was filled with the first part of a larger message, that's
+    // valid data. The rest is returned by the next read.
+    let res = match res {
+        Err(_) if io.mid_message => Ok(status.bytes_transferred() as usize),
+        res => res,
+    };
+    let mut eof = false;
+    match mem::replace(&mut io.read, State::None) {
+        State::Pending(mut buf, offset) => match res {
+            Ok(n) => {
+                debug_assert_eq!(status.bytes_transferred() as usize, n);
+                unsafe { buf.set_len(offset + n) };
+                io.read = State::Ok(buf, offset);
+                me.adapt_read_size(&mut io, n);
+            }
+            Err(e) => {
+                debug_assert_eq!(status.bytes_transferred(), 0);
+                me.put_buffer(buf);
+                eof = e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32);
+                io.read = io.failed(e);
+            }
+        },
+        // The caller's buffer was filled, see `read_overlapped_into`.
+        State::DirectPending => {
+            io.read = match res {
+                Ok(n) => State::DirectOk(n),
+                Err(e) => {
+                    eof = e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32);
+                    io.failed(e)
+                }
+            };
+        }
+        _ => unreachable!(),
+    }
+
+    // Flag our readiness that we've got data.
+    Inner::notify(&me, &mut io, Op::Read, events);
+    drop(io);
+
+    if eof {
+        me.fire(PipeEvent::Eof);
+    }
+}
+
+fn write_done(status: &OVERLAPPED_ENTRY, events: Option<&mut Vec<Event>>) {
+    let status = CompletionStatus::from_entry(status);
+
+    // Acquire the `Arc<Inner>`. Note that we should be guaranteed that the
+    // refcount is available to us due to the `mem::forget` in
+    // `maybe_schedule_write` above.
+    let me = unsafe { Arc::from_raw(Inner::ptr_from_write_overlapped(status.overlapped())) };
+
+    // Make the state change out of `Pending`. If we wrote the entire buffer
+    // then we're writable again and otherwise we schedule another write.
+    let mut io = me.io.lock().unwrap();
+    io.write_deadline = None;
+    // Waiters only observe the new state once `io` is unlocked.
+    me.write_cond.notify_all();
+    let cancelled = mem::replace(&mut io.write_timed_out, false);
+    if mem::replace(&mut io.discard_write, false) {
+        // The write belonged to a previous client, see `NamedPipe::recycle`.
+        if let State::Pending(buf, _) = mem::replace(&mut io.write, State::None) {
+            me.put_buffer(buf);
+        }
+        return;
+    }
+    io.last_write_len = status.bytes_transferred() as usize;
+    me.bytes_written
+        .fetch_add(u64::from(status.bytes_transferred()), Relaxed);
+    if mem::replace(&mut io.retract_write, false) {
+        // `try_write` reported that nothing was written, see there.
+        if let State::Pending(buf, _) = mem::replace(&mut io.write, State::None) {
+            me.put_buffer(buf);
+        }
+        let n = status.bytes_transferred();
+        if n != 0 {
+            let msg = format!("cancelled named pipe write transferred {} bytes", n);
+            io.write = io.failed(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        Inner::notify_writable_edge(&me, &mut io, Op::Write, events);
+        return;
+    }
+    let (buf, pos) = match mem::replace(&mut io.write, State::None) {
+        // `Ok` here means, that the operation was completed immediately
+        // `bytes_transferred` is already reported to a client
+        State::Ok(buf, _) => {
+            me.put_buffer(buf);
+            Inner::notify_writable_edge(&me, &mut io, Op::Write, events);
+            return;
+        }
+        State::Pending(buf, pos) => (buf, pos),
+        // The caller's buffer was written from, see `write_overlapped_from`.
+        State::DirectPending => {
+            io.write = match unsafe { me.handle.result(status.overlapped()) } {
+                Ok(n) => State::DirectOk(n),
+                Err(_) if cancelled => io.failed(timed_out("write")),
+                Err(e) => io.failed(e),
+            };
+            Inner::notify_writable_edge(&me, &mut io, Op::Write, events);
+            return;
+        }
+        _ => unreachable!(),
+    };
+
+    match unsafe { me.handle.result(status.overlapped()) } {
+        Ok(n) => {
+            debug_assert_eq!(status.bytes_transferred() as usize, n);
+            let new_pos = pos + n;
+            if new_pos == buf.len() {
+                me.put_buffer(buf);
+                Inner::notify_writable_edge(&me, &mut io, Op::Write, events);
+            } else {
+                Inner::schedule_write(&me, buf, new_pos, &mut io, events);
+            }
+        }
+        Err(e) => {
+            me.put_buffer(buf);
+            io.write = io.failed(if cancelled { timed_out("write") } else { e });
+            Inner::notify_writable_edge(&me, &mut io, Op::Write, events);
+        }
+    }
+}
+
+/// Body of the thread cancelling reads, writes and connects pending past their
+/// deadline, see `NamedPipe::set_read_timeout`.
+///
+/// Only a weak reference is held so the thread doesn't keep the pipe alive. It
+/// exits once the pipe is gone or no timeout or connect deadline is set
+/// anymore.
+fn run_timer(weak: Weak<Inner>) {
+    loop {
+        let me = match weak.upgrade() {
+            Some(me) => me,
+            None => return,
+        };
+        let mut io = me.io.lock().unwrap();
+        if !io.needs_timer() {
+            io.timer = None;
+            return;
+        }
+
+        // The completion of a cancelled operation is reported as timed out by
+        // `read_done`/`write_done`.
+        let now = Instant::now();
+        if io.read_deadline.map_or(false, |deadline| deadline <= now) {
+            io.read_deadline = None;
+            io.read_timed_out = true;
+            drop(unsafe { cancel(&me.handle, &me.read) });
+        }
+        if io.write_deadline.map_or(false, |deadline| deadline <= now) {
+            io.write_deadline = None;
+            io.write_timed_out = true;
+            drop(unsafe { cancel(&me.handle, &me.write) });
+        }
+        let mut failed = false;
+        if io
+            .connect_deadline
+            .map_or(false, |deadline| deadline <= now)
+        {
+            io.connect_deadline = None;
+            if io.connect_retry.take().is_some() {
+                // No connect is in flight while waiting for a retry, so there's
+                // no completion to report the time out.
+                io.connect_error = Some(timed_out("connect"));
+                io.writable_notified = false;
+                failed = true;
+            } else {
+                io.connect_timed_out = true;
+                drop(unsafe { cancel(&me.handle, &me.connect) });
+            }
+        }
+        let mut retry = None;
+        if let Some((at, attempt)) = io.connect_retry {
+            if at <= now {
+                io.connect_retry = None;
+                retry = Some(attempt);
+            }
+        }
+
+        let retry_at = io.connect_retry.map(|(at, _)| at);
+        let next = [
+            io.read_deadline,
+            io.write_deadline,
+            io.connect_deadline,
+            retry_at,
+        ]
+        .iter()
+        .filter_map(|deadline| *deadline)
+        .min()
+        .unwrap_or(now + TIMER_IDLE);
+        drop(io);
+        if let Some(attempt) = retry {
+            if let Err(e) = Inner::start_connect(&me, attempt) {
+                let mut io = me.io.lock().unwrap();
+                io.connect_error = Some(e);
+                io.writable_notified = false;
+                failed = true;
+            }
+        }
+        if failed {
+            Inner::finish_connect(&me, false, None);
+        }
+        drop(me);
+        thread::park_timeout(next.saturating_duration_since(now));
+    }
+}
+
+unsafe fn cancel<T: AsRawHandle>(handle: &T, overlapped: &Overlapped) -> io::Result<()> {
+    let ret = CancelIoEx(handle.as_raw_handle(), overlapped.as_ptr() as *mut _);
+    // `CancelIoEx` returns 0 on error:
+    // https://docs.microsoft.com/en-us/windows/win32/fileio/cancelioex-func
+    if ret == 0 {
+        match io::Error::last_os_error() {
+            // The operation already completed, there's nothing left to cancel.
+            ref e if e.raw_os_error() == Some(ERROR_NOT_FOUND as i32) => Ok(()),
+            e => Err(e),
+        }
+    } else {
+        Ok(())
+    }
+}
+
+#[test]
+fn cancel_completed() {
+    let (_server, client) = anonymous_pipe().unwrap();
+    let overlapped = Overlapped::new(write_done);
+    // Nothing reads from the pipe, but the write fits into its buffer and
+    // completes right away.
+    let res = unsafe {
+        client
+            .inner
+            .handle
+            .write_overlapped(b"1234", overlapped.as_mut_ptr())
+    };
+    assert_eq!(res.unwrap(), Some(4));
+    assert!(unsafe { cancel(&client.inner.handle, &overlapped) }.is_ok());
+}
+
+#[test]
+fn completion_key() {
+    use crate::Poll;
+
+    let addr = format!(r"\\.\pipe\mio-completion-key-{}", process::id());
+    let mut server = NamedPipeBuilder::new(&addr)
+        .completion_key(42)
+        .create()
+        .unwrap();
+    let client = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(FILE_FLAG_OVERLAPPED)
+        .open(&addr)
+        .unwrap();
+    let client = unsafe { NamedPipe::from_raw_handle(client.into_raw_handle()) };
+    let poll = Poll::new().unwrap();
+    server
+        .register(poll.registry(), Token(0), Interest::READABLE)
+        .unwrap();
+    assert_eq!(client.write_blocking(b"1", None).unwrap(), 1);
+
+    // The read scheduled by registering completes under the key.
+    let port = poll::selector(poll.registry()).inner.port();
+    let status = port.get(Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(status.token(), 42);
+    // Releases the reference to `inner` held by the read, deregistering
+    // first so no event is posted for it.
+    server.deregister(poll.registry()).unwrap();
+    unsafe {
+        dispatch_completion(
+            status.overlapped(),
+            status.bytes_transferred() as usize,
+            None,
+        )
+    };
+}
+
+#[test]
+fn reconnect_register_failure() {
+    use crate::Poll;
+
+    let addr = format!(r"\\.\pipe\mio-reconnect-{}", process::id());
+    let _server = NamedPipe::new(&addr).unwrap();
+    let mut client = ReconnectingClient::connect(&addr).unwrap();
+    let poll = Poll::new().unwrap();
+    client
+        .register(poll.registry(), Token(1), Interest::READABLE)
+        .unwrap();
+
+    // A pipe in blocking mode can't be registered.
+    let _second = NamedPipe::new(&addr).unwrap();
+    let pipe = open_client(&client.addr, 0).unwrap();
+    pipe.set_blocking(true).unwrap();
+    let err = client.replace(pipe).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert!(client.broken);
+    assert_eq!(client.reconnects(), 0);
+    assert_eq!(client.get_ref().registered_token(), Some(Token(1)));
+
+    // The next operation reconnects rather than using the old connection.
+    let _third = NamedPipe::new(&addr).unwrap();
+    let err = client.read(&mut [0; 4]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    assert!(!client.broken);
+    assert_eq!(client.reconnects(), 1);
+    assert_eq!(client.get_ref().registered_token(), Some(Token(1)));
+}
+
+/// Operation whose completion is reported to the `Poll` the pipe is
+/// registered with, see `Inner::notify`.
+///
+/// The discriminants are the token offsets of `NamedPipeBuilder::sub_tokens`.
+#[derive(Clone, Copy)]
+enum Op {
+    Connect = 0,
+    Read = 1,
+    Write = 2,
+}
+
+impl Op {
+    fn readiness(self) -> Ready {
+        match self {
+            Op::Read => Ready::READABLE,
+            Op::Connect | Op::Write => Ready::WRITABLE,
+        }
+    }
+
+    /// Bit of the operation in `Io.posted`.
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl Io {
+    /// Whether the timer thread is needed, see `run_timer`.
+    fn needs_timer(&self) -> bool {
+        self.read_timeout.is_some()
+            || self.write_timeout.is_some()
+            || self.connect_deadline.is_some()
+            || self.connect_retry.is_some()
+    }
+
+    /// Returns the deadline of an operation issued now with `timeout`, waking
+    /// up the timer thread to account for it.
+    fn start_deadline(&self, timeout: Option<Duration>) -> Option<Instant> {
+        let timeout = timeout?;
+        if let Some(timer) = &self.timer {
+            timer.unpark();
+        }
+        Some(Instant::now() + timeout)
+    }
+
+    /// Returns the state of an overlapped operation that failed with `err`,
+    /// recording its OS error code for `NamedPipe::last_os_error_code`.
+    fn failed(&mut self, err: io::Error) -> State {
+        self.last_os_error = err.raw_os_error();
+        State::Err(err)
+    }
+
+    /// Whether completions of this pipe are processed, i.e. it's registered
+    /// or in APC mode.
+    fn is_active(&self) -> bool {
+        self.token.is_some() || self.apc || self.foreign_port
+    }
+
+    /// Returns an error if the pipe is in a mode that excludes registering it
+    /// with a `Registry`.
+    fn check_registrable(&self) -> io::Result<()> {
+        if self.blocking || self.apc {
+            return Err(blocking_mode());
+        }
+        if self.foreign_port {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "named pipe is associated with another completion port",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks whether a new write can be issued, returning the error of the
+    /// previous write if that one failed.
+    fn check_writable(&mut self) -> io::Result<()> {
+        if !self.is_active() {
+            return Err(not_registered());
+        }
+
+        match self.write {
+            State::None => Ok(()),
+            State::Err(_) => match mem::replace(&mut self.write, State::None) {
+                State::Err(e) => Err(e),
+                // `io` is locked, so this branch is unreachable
+                _ => unreachable!(),
+            },
+            State::Pending(..) => {
+                self.would_block_count += 1;
+                Err(would_block())
+            }
+            // any other state should be handled in `write_done`
+            _ => Err(would_block()),
+        }
+    }
+
+    fn check_association(&self, registry: &Registry, required: bool) -> io::Result<()> {
+        match self.selector {
+            Some(ref selector) if !Arc::ptr_eq(selector, &poll::selector(registry).inner) => {
+                Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "I/O source already registered with a different `Registry`",
+                ))
+            }
+            None if required => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "I/O source not registered with `Registry`",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Event handles signalled when the overlapped operations of a [`NamedPipe`]
+/// complete, see [`NamedPipe::set_overlapped_events`].
+///
+/// No event is used for an operation unless one is set. The events are
+/// closed when this is dropped.
+#[derive(Debug, Default)]
+pub struct OverlappedEvents {
+    connect: Option<OwnedEvent>,
+    read: Option<OwnedEvent>,
+    write: Option<OwnedEvent>,
+}
+
+impl OverlappedEvents {
+    /// Sets the event of the connect operation, closing the one set before.
+    ///
+    /// # Safety
+    ///
+    /// `event` must be a valid event handle owned by the caller, which hands
+    /// over its ownership: it's closed along with these events.
+    pub unsafe fn set_connect(&mut self, event: RawHandle) -> &mut OverlappedEvents {
+        self.connect = Some(OwnedEvent(event as HANDLE));
+        self
+    }
+
+    /// Same as [`set_connect`] for the internal read operation.
+    ///
+    /// # Safety
+    ///
+    /// See [`set_connect`].
+    ///
+    /// [`set_connect`]: OverlappedEvents::set_connect
+    pub unsafe fn set_read(&mut self, event: RawHandle) -> &mut OverlappedEvents {
+        self.read = Some(OwnedEvent(event as HANDLE));
+        self
+    }
+
+    /// Same as [`set_connect`] for the internal write operation.
+    ///
+    /// # Safety
+    ///
+    /// See [`set_connect`].
+    ///
+    /// [`set_connect`]: OverlappedEvents::set_connect
+    pub unsafe fn set_write(&mut self, event: RawHandle) -> &mut OverlappedEvents {
+        self.write = Some(OwnedEvent(event as HANDLE));
+        self
+    }
+}
+
+/// An event handle closed when dropped.
+#[derive(Debug)]
+struct OwnedEvent(HANDLE);
+
+// `HANDLE` is a pointer, but the event is only ever accessed through the
+// system calls, which may be used from any thread.
+unsafe impl Send for OwnedEvent {}
+unsafe impl Sync for OwnedEvent {}
+
+impl Drop for OwnedEvent {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Disconnects the client of a [`NamedPipe`] when dropped, returned by
+/// [`NamedPipe::connection_guard`].
+#[derive(Debug)]
+pub struct ConnectionGuard<'a> {
+    pipe: &'a NamedPipe,
+}
+
+impl Deref for ConnectionGuard<'_> {
+    type Target = NamedPipe;
+
+    fn deref(&self) -> &NamedPipe {
+        self.pipe
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.pipe.disconnect();
+    }
+}
+
+/// Future resolving once a [`NamedPipe`] is connected, returned by
+/// [`NamedPipe::connected`].
+#[cfg(feature = "future")]
+#[derive(Debug)]
+pub struct Connected<'a> {
+    pipe: &'a NamedPipe,
+}
+
+#[cfg(feature = "future")]
+impl std::future::Future for Connected<'_> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        // Store the waker before checking, so a completion in between isn't
+        // missed.
+        *self.pipe.inner.connect_waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.pipe.poll_connected() {
+            Ok(true) => task::Poll::Ready(Ok(())),
+            Ok(false) => task::Poll::Pending,
+            Err(e) => task::Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// Length prefix of the frames of a [`FramedPipe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameLength {
+    /// Big-endian `u16`.
+    U16Be,
+    /// Little-endian `u16`.
+    U16Le,
+    /// Big-endian `u32`.
+    U32Be,
+    /// Little-endian `u32`.
+    U32Le,
+}
+
+impl FrameLength {
+    /// Size of the prefix in bytes.
+    fn size(self) -> usize {
+        match self {
+            FrameLength::U16Be | FrameLength::U16Le => 2,
+            FrameLength::U32Be | FrameLength::U32Le => 4,
+        }
+    }
+
+    /// Largest frame the prefix can describe.
+    fn max(self) -> u64 {
+        match self {
+            FrameLength::U16Be | FrameLength::U16Le => u64::from(u16::max_value()),
+            FrameLength::U32Be | FrameLength::U32Le => u64::from(u32::max_value()),
+        }
+    }
+
+    fn encode(self, len: usize, out: &mut Vec<u8>) {
+        match self {
+            FrameLength::U16Be => out.extend_from_slice(&(len as u16).to_be_bytes()),
+            FrameLength::U16Le => out.extend_from_slice(&(len as u16).to_le_bytes()),
+            FrameLength::U32Be => out.extend_from_slice(&(len as u32).to_be_bytes()),
+            FrameLength::U32Le => out.extend_from_slice(&(len as u32).to_le_bytes()),
+        }
+    }
+
+    /// Decodes the prefix at the start of `buf`, which holds at least
+    /// `size()` bytes.
+    fn decode(self, buf: &[u8]) -> usize {
+        match self {
+            FrameLength::U16Be => u16::from_be_bytes([buf[0], buf[1]]) as usize,
+            FrameLength::U16Le => u16::from_le_bytes([buf[0], buf[1]]) as usize,
+            FrameLength::U32Be => u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize,
+            FrameLength::U32Le => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize,
         }
     }
 }
 
-impl<'a> Write for &'a NamedPipe {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // Make sure there's no writes pending
-        let mut io = self.inner.io.lock().unwrap();
+/// Reads and writes length-prefixed frames on top of the byte stream of a
+/// [`NamedPipe`].
+///
+/// Partial frames are kept across readiness events: [`next_frame`] returns a
+/// "would block" error until a frame is complete and [`send_frame`] queues
+/// what can't be written right away. Register the framed pipe itself, it
+/// forwards to the named pipe.
+///
+/// [`next_frame`]: FramedPipe::next_frame
+/// [`send_frame`]: FramedPipe::send_frame
+#[derive(Debug)]
+pub struct FramedPipe {
+    pipe: NamedPipe,
+    length: FrameLength,
+    // See `FramedPipe::set_max_frame_size`.
+    max_frame_size: usize,
+    // Received bytes not returned as a frame yet.
+    read_buf: Vec<u8>,
+    // Encoded frames not written yet, from `write_pos` on.
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
 
-        if io.token.is_none() {
-            return Err(would_block());
+impl FramedPipe {
+    /// Wraps `pipe`, prefixing every frame with its length encoded as
+    /// `length`.
+    pub fn new(pipe: NamedPipe, length: FrameLength) -> FramedPipe {
+        let max_frame_size = pipe
+            .inner
+            .max_message_size
+            .unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+        FramedPipe {
+            pipe,
+            length,
+            max_frame_size,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            write_pos: 0,
         }
+    }
 
-        match io.write {
-            State::None => {}
-            State::Err(_) => match mem::replace(&mut io.write, State::None) {
-                State::Err(e) => return Err(e),
-                // `io` is locked, so this branch is unreachable
-                _ => unreachable!(),
-            },
-            // any other state should be handled in `write_done`
-            _ => {
-                return Err(would_block());
+    /// Sets the size of the largest frame accepted by [`next_frame`].
+    ///
+    /// The length of a frame is chosen by the peer, so without a limit it
+    /// could make this buffer up to 4 GiB before the frame is complete.
+    /// Frames announced to be larger fail with an error of kind
+    /// `InvalidData` instead.
+    ///
+    /// Defaults to the maximum message size of a message mode named pipe, see
+    /// [`NamedPipeBuilder::max_message_size`], or 16 MiB otherwise.
+    ///
+    /// [`next_frame`]: FramedPipe::next_frame
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size;
+    }
+
+    /// Returns a reference to the named pipe.
+    pub fn get_ref(&self) -> &NamedPipe {
+        &self.pipe
+    }
+
+    /// Returns the named pipe, discarding any partial frames.
+    pub fn into_inner(self) -> NamedPipe {
+        self.pipe
+    }
+
+    /// Returns the next complete frame.
+    ///
+    /// Returns a "would block" error while no complete frame was received,
+    /// `Ok(None)` once the peer disconnected at a frame boundary and an
+    /// error of kind `UnexpectedEof` if it disconnected in the middle of a
+    /// frame. A frame larger than the limit set by [`set_max_frame_size`]
+    /// fails with an error of kind `InvalidData`, without buffering it.
+    ///
+    /// [`set_max_frame_size`]: FramedPipe::set_max_frame_size
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let prefix = self.length.size();
+        loop {
+            if self.read_buf.len() >= prefix {
+                let len = self.length.decode(&self.read_buf);
+                if len > self.max_frame_size {
+                    let msg = format!(
+                        "frame of {} bytes exceeds the maximum frame size of {} bytes",
+                        len, self.max_frame_size
+                    );
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+                if self.read_buf.len() - prefix >= len {
+                    let rest = self.read_buf.split_off(prefix + len);
+                    let mut frame = mem::replace(&mut self.read_buf, rest);
+                    frame.drain(..prefix);
+                    return Ok(Some(frame));
+                }
+            }
+
+            let mut buf = [0; 4 * 1024];
+            match (&self.pipe).read(&mut buf)? {
+                0 if self.read_buf.is_empty() => return Ok(None),
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "named pipe disconnected in the middle of a frame",
+                    ))
+                }
+                n => self.read_buf.extend_from_slice(&buf[..n]),
             }
         }
+    }
 
-        // Move `buf` onto the heap and fire off the write
-        let mut owned_buf = self.inner.get_buffer();
-        owned_buf.extend(buf);
-        match Inner::maybe_schedule_write(&self.inner, owned_buf, 0, &mut io)? {
-            // Some bytes are written immediately
-            Some(n) => Ok(n),
-            // Write operation is anqueued for whole buffer
-            None => Ok(buf.len()),
+    /// Writes `frame` prefixed with its length.
+    ///
+    /// Whatever can't be written right away is queued and written by
+    /// [`flush`], which should be called on the next writable event. Returns
+    /// an error of kind `InvalidInput` if the frame is too large for the
+    /// length prefix.
+    ///
+    /// [`flush`]: FramedPipe::flush
+    pub fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() as u64 > self.length.max() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame is too large for its length prefix",
+            ));
+        }
+        self.length.encode(frame.len(), &mut self.write_buf);
+        self.write_buf.extend_from_slice(frame);
+        match self.flush() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res,
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
+    /// Writes the queued frames.
+    ///
+    /// Returns a "would block" error if some remain queued.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while self.write_pos < self.write_buf.len() {
+            match (&self.pipe).write(&self.write_buf[self.write_pos..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.write_pos += n,
+                Err(e) => return Err(e),
+            }
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
         Ok(())
     }
 }
 
-impl Source for NamedPipe {
-    fn register(&mut self, _registry: &Registry, _token: Token, _: Interest) -> io::Result<()> {
-        unimplemented!()
+impl Source for FramedPipe {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.pipe.register(registry, token, interests)
     }
 
-    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> io::Result<()> {
-        let mut io = self.inner.io.lock().unwrap();
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.pipe.reregister(registry, token, interests)
+    }
 
-        io.check_association(registry, true)?;
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.pipe.deregister(registry)
+    }
+}
 
-        io.token = Some(token);
-        drop(io);
+/// Opens a client of the named pipe at `addr`, retrying up to `attempts`
+/// times while the server is busy or not (re)started yet.
+fn open_client(addr: &OsStr, attempts: u32) -> io::Result<NamedPipe> {
+    let name: Vec<u16> = addr.encode_wide().chain(Some(0)).collect();
+    let mut attempt = 0;
+    loop {
+        let res = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(addr);
+        let backoff = 1 << cmp::min(attempt, 10);
+        match res {
+            Ok(file) => {
+                let mut pipe = NamedPipe::from_handle(file);
+                // The pipe was just created so nothing else references `inner`.
+                Arc::get_mut(&mut pipe.inner).unwrap().addr = Some(addr.to_owned());
+                return Ok(pipe);
+            }
+            // All instances are connected, wait for one to become available.
+            Err(ref e)
+                if attempt < attempts && e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) =>
+            {
+                drop(syscall!(
+                    WaitNamedPipeW(name.as_ptr(), backoff),
+                    PartialEq::eq,
+                    FALSE
+                ));
+            }
+            // The server isn't running (yet).
+            Err(ref e)
+                if attempt < attempts && e.raw_os_error() == Some(ERROR_FILE_NOT_FOUND as i32) =>
+            {
+                thread::sleep(Duration::from_millis(u64::from(backoff)));
+            }
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+    }
+}
 
-        Inner::post_register(&self.inner, None);
+/// A client of a [`NamedPipe`] server which reconnects when the server goes
+/// away, e.g. because it restarted.
+///
+/// Once reading returns the end of the stream, or reading or writing fails
+/// as the pipe is broken, the client opens a new connection to the same
+/// address and registers it with the token it was registered with. The
+/// operation noticing the disconnect then returns an error of kind
+/// `ConnectionReset`: the new connection is ready to be used, but the server
+/// has none of the connection's state and data written shortly before the
+/// disconnect may have been lost, so any handshake needs to be replayed. If
+/// reconnecting fails that error is returned instead, and the next operation
+/// tries again.
+///
+/// Opening a connection blocks the calling thread while waiting for the
+/// server, using `WaitNamedPipeW` while all its instances are busy and
+/// sleeping while it isn't running, starting at 1ms and doubling every
+/// attempt, see [`set_max_attempts`]. As reconnects happen within `read` and
+/// `write` this blocks the event loop, for up to about a second with the
+/// default number of attempts. Lower it to bound the stall further.
+///
+/// [`set_max_attempts`]: ReconnectingClient::set_max_attempts
+#[derive(Debug)]
+pub struct ReconnectingClient {
+    pipe: NamedPipe,
+    addr: OsString,
+    max_attempts: u32,
+    // Registration to carry over to new connections, see `Source` below.
+    registration: Option<(Registry, Token, Interest)>,
+    // The connection is gone but reconnecting failed, see `reconnect`.
+    broken: bool,
+    reconnects: u64,
+}
 
-        Ok(())
+impl ReconnectingClient {
+    /// Connects to the named pipe server at `addr`.
+    ///
+    /// `addr` is normalized like [`NamedPipeBuilder::normalize_addr`] does.
+    pub fn connect<A: AsRef<OsStr>>(addr: A) -> io::Result<ReconnectingClient> {
+        let addr = pipe_addr(addr.as_ref())?;
+        let max_attempts = 10;
+        Ok(ReconnectingClient {
+            pipe: open_client(&addr, max_attempts)?,
+            addr,
+            max_attempts,
+            registration: None,
+            broken: false,
+            reconnects: 0,
+        })
     }
 
-    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
-        let mut io = self.inner.io.lock().unwrap();
+    /// Sets how often opening a connection is retried while the server is
+    /// busy or not running before giving up.
+    ///
+    /// Defaults to 10, waiting for about a second in total.
+    pub fn set_max_attempts(&mut self, attempts: u32) {
+        self.max_attempts = attempts;
+    }
 
-        io.check_association(registry, true)?;
+    /// Returns a reference to the named pipe of the current connection.
+    pub fn get_ref(&self) -> &NamedPipe {
+        &self.pipe
+    }
 
-        if io.token.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "I/O source not registered with `Registry`",
-            ));
+    /// Returns how often this client reconnected.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Replaces the broken connection with a new one, returning the error
+    /// signalling the reconnect.
+    ///
+    /// If that fails the client stays `broken`, so the next operation calls
+    /// this again instead of using the old connection.
+    fn reconnect(&mut self) -> io::Result<usize> {
+        self.broken = true;
+        let pipe = open_client(&self.addr, self.max_attempts)?;
+        self.replace(pipe)
+    }
+
+    /// Switches over to the new connection `pipe`, see `reconnect`.
+    fn replace(&mut self, mut pipe: NamedPipe) -> io::Result<usize> {
+        // The old pipe is only deregistered once the new one took its place,
+        // so a failure leaves the registration as it was.
+        if let Some((registry, token, interests)) = &self.registration {
+            pipe.register(registry, *token, *interests)?;
+            drop(self.pipe.deregister(registry));
         }
+        self.pipe = pipe;
+        self.broken = false;
+        self.reconnects += 1;
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "named pipe client reconnected",
+        ))
+    }
+}
 
-        io.token = None;
-        Ok(())
+/// Whether `err` means the connection of a client is gone.
+fn is_disconnected(err: &io::Error) -> bool {
+    match PipeError::classify(err) {
+        PipeError::BrokenPipe | PipeError::NoData => true,
+        _ => false,
     }
 }
 
-impl AsRawHandle for NamedPipe {
-    fn as_raw_handle(&self) -> RawHandle {
-        self.inner.handle.as_raw_handle()
+impl Read for ReconnectingClient {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.broken {
+            return self.reconnect();
+        }
+        match self.pipe.read(buf) {
+            Ok(0) if !buf.is_empty() => self.reconnect(),
+            Err(ref e) if is_disconnected(e) => self.reconnect(),
+            res => res,
+        }
     }
 }
 
-impl fmt::Debug for NamedPipe {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.inner.handle.fmt(f)
+impl Write for ReconnectingClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.broken {
+            return self.reconnect();
+        }
+        match self.pipe.write(buf) {
+            Err(ref e) if is_disconnected(e) => self.reconnect(),
+            res => res,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pipe.flush()
     }
 }
 
-impl Drop for NamedPipe {
-    fn drop(&mut self) {
-        // Cancel pending reads/connects, but don't cancel writes to ensure that
-        // everything is flushed out.
-        unsafe {
-            if self.inner.connecting.load(SeqCst) {
-                drop(cancel(&self.inner.handle, &self.inner.connect));
-            }
+impl Source for ReconnectingClient {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        let clone = registry.try_clone()?;
+        self.pipe.register(registry, token, interests)?;
+        self.registration = Some((clone, token, interests));
+        Ok(())
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.pipe.reregister(registry, token, interests)?;
+        if let Some(registration) = &mut self.registration {
+            registration.1 = token;
+            registration.2 = interests;
+        }
+        Ok(())
+    }
 
-            let io = self.inner.io.lock().unwrap();
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.pipe.deregister(registry)?;
+        self.registration = None;
+        Ok(())
+    }
+}
 
-            match io.read {
-                State::Pending(..) => {
-                    drop(cancel(&self.inner.handle, &self.inner.read));
+/// Reads whole messages of a message mode [`NamedPipe`], returned by
+/// [`NamedPipe::messages`].
+#[derive(Debug)]
+pub struct Messages<'a> {
+    pipe: &'a NamedPipe,
+    // Parts of the message received so far.
+    partial: Vec<u8>,
+}
+
+impl Messages<'_> {
+    /// Reads the next complete message into `buf`, replacing its contents,
+    /// and returns its length.
+    ///
+    /// Returns `Ok(None)` once the client disconnected at a message boundary
+    /// and an error of kind `UnexpectedEof` if it disconnected in the middle
+    /// of a message. Returns a "would block" error while no complete message
+    /// was received, the parts received so far are kept for the next call.
+    pub fn next_message(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<usize>> {
+        loop {
+            match Inner::read_message_part(&self.pipe.inner, &mut self.partial)? {
+                Some(true) => {
+                    buf.clear();
+                    buf.append(&mut self.partial);
+                    return Ok(Some(buf.len()));
+                }
+                Some(false) => {}
+                None if self.partial.is_empty() => return Ok(None),
+                None => {
+                    self.partial.clear();
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "named pipe disconnected in the middle of a message",
+                    ));
                 }
-                _ => {}
             }
         }
     }
 }
 
-impl Inner {
-    /// Schedules a read to happen in the background, executing an overlapped
-    /// operation.
-    ///
-    /// This function returns `true` if a normal error happens or if the read
-    /// is scheduled in the background. If the pipe is no longer connected
-    /// (ERROR_PIPE_LISTENING) then `false` is returned and no read is
-    /// scheduled.
-    fn schedule_read(me: &Arc<Inner>, io: &mut Io, events: Option<&mut Vec<Event>>) -> bool {
-        // Check to see if a read is already scheduled/completed
-        match io.read {
-            State::None => {}
-            _ => return true,
+/// A weak reference to a [`NamedPipe`], returned by
+/// [`NamedPipe::downgrade`].
+#[derive(Clone)]
+pub struct WeakNamedPipe {
+    inner: Weak<Inner>,
+}
+
+impl WeakNamedPipe {
+    /// Returns the named pipe, or `None` if it was dropped already.
+    pub fn upgrade(&self) -> Option<NamedPipe> {
+        let inner = self.inner.upgrade()?;
+        let mut owners = inner.owners.load(SeqCst);
+        loop {
+            if owners == 0 {
+                return None;
+            }
+            match inner
+                .owners
+                .compare_exchange(owners, owners + 1, SeqCst, SeqCst)
+            {
+                Ok(_) => return Some(NamedPipe { inner }),
+                Err(current) => owners = current,
+            }
         }
+    }
+}
 
-        // Allocate a buffer and schedule the read.
-        let mut buf = me.get_buffer();
-        let e = unsafe {
-            let overlapped = me.read.as_ptr() as *mut _;
-            let slice = slice::from_raw_parts_mut(buf.as_mut_ptr(), buf.capacity());
-            me.handle.read_overlapped(slice, overlapped)
-        };
+impl fmt::Debug for WeakNamedPipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeakNamedPipe").finish()
+    }
+}
 
-        match e {
-            // See `NamedPipe::connect` above for the rationale behind `forget`
-            Ok(_) => {
-                io.read = State::Pending(buf, 0); // 0 is ignored on read side
-                mem::forget(me.clone());
-                true
-            }
+/// The read half of a [`NamedPipe`], returned by [`NamedPipe::into_split`].
+pub struct OwnedReadHalf {
+    inner: Arc<Inner>,
+}
 
-            // If ERROR_PIPE_LISTENING happens then it's not a real read error,
-            // we just need to wait for a connect.
-            Err(ref e) if e.raw_os_error() == Some(ERROR_PIPE_LISTENING as i32) => false,
+/// The write half of a [`NamedPipe`], returned by [`NamedPipe::into_split`].
+pub struct OwnedWriteHalf {
+    inner: Arc<Inner>,
+}
 
-            // If some other error happened, though, we're now readable to give
-            // out the error.
-            Err(e) => {
-                io.read = State::Err(e);
-                io.notify_readable(events);
-                true
-            }
-        }
+impl Read for OwnedReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Inner::read(&self.inner, buf)
     }
+}
 
-    /// Maybe schedules overlapped write operation.
-    ///
-    /// * `None` means that overlapped operation was enqueued
-    /// * `Some(n)` means that `n` bytes was immediately written.
-    ///   Note, that `write_done` will fire anyway to clean up the state.
-    fn maybe_schedule_write(
-        me: &Arc<Inner>,
-        buf: Vec<u8>,
-        pos: usize,
-        io: &mut Io,
-    ) -> io::Result<Option<usize>> {
-        // Very similar to `schedule_read` above, just done for the write half.
-        let e = unsafe {
-            let overlapped = me.write.as_ptr() as *mut _;
-            me.handle.write_overlapped(&buf[pos..], overlapped)
-        };
+impl Write for OwnedWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
 
-        // See `connect` above for the rationale behind `forget`
-        match e {
-            // `n` bytes are written immediately
-            Ok(Some(n)) => {
-                io.write = State::Ok(buf, pos);
-                mem::forget(me.clone());
-                Ok(Some(n))
-            }
-            // write operation is enqueued
-            Ok(None) => {
-                io.write = State::Pending(buf, pos);
-                mem::forget(me.clone());
-                Ok(None)
-            }
-            Err(e) => Err(e),
-        }
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        Inner::write_vectored(&self.inner, bufs)
     }
 
-    fn post_register(me: &Arc<Inner>, mut events: Option<&mut Vec<Event>>) {
-        let mut io = me.io.lock().unwrap();
-        if Inner::schedule_read(&me, &mut io, events.as_mut().map(|ptr| &mut **ptr)) {
-            if let State::None = io.write {
-                io.notify_writable(events);
-            }
-        }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
+}
 
-    fn get_buffer(&self) -> Vec<u8> {
-        self.pool.lock().unwrap().get(4 * 1024)
+impl Drop for OwnedReadHalf {
+    fn drop(&mut self) {
+        self.inner.release();
     }
+}
 
-    fn put_buffer(&self, buf: Vec<u8>) {
-        self.pool.lock().unwrap().put(buf)
+impl Drop for OwnedWriteHalf {
+    fn drop(&mut self) {
+        self.inner.release();
     }
 }
 
-unsafe fn cancel<T: AsRawHandle>(handle: &T, overlapped: &Overlapped) -> io::Result<()> {
-    let ret = CancelIoEx(handle.as_raw_handle(), overlapped.as_ptr() as *mut _);
-    // `CancelIoEx` returns 0 on error:
-    // https://docs.microsoft.com/en-us/windows/win32/fileio/cancelioex-func
-    if ret == 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(())
+impl fmt::Debug for OwnedReadHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OwnedReadHalf")
+            .field(&self.inner.handle)
+            .finish()
     }
 }
 
-impl Io {
-    fn check_association(&self, _registry: &Registry, _required: bool) -> io::Result<()> {
-        unimplemented!()
+impl fmt::Debug for OwnedWriteHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OwnedWriteHalf")
+            .field(&self.inner.handle)
+            .finish()
     }
+}
+
+/// A source of the buffers used for the internal buffering of a
+/// [`NamedPipe`], see [`NamedPipeBuilder::buffer_alloc`].
+///
+/// The default implementation is a [`BufferPool`] behind a `Mutex`.
+pub trait BufferAlloc: Send + Sync {
+    /// Returns a buffer with a capacity of at least `cap` bytes.
+    ///
+    /// The contents of the returned buffer are ignored.
+    fn get(&self, cap: usize) -> Vec<u8>;
 
-    fn notify_readable(&self, _events: Option<&mut Vec<Event>>) {
-        unimplemented!()
+    /// Returns a buffer previously handed out by `get` once the pipe is done
+    /// with it.
+    fn put(&self, buf: Vec<u8>);
+}
+
+impl BufferAlloc for Mutex<BufferPool> {
+    fn get(&self, cap: usize) -> Vec<u8> {
+        self.lock().unwrap().get_at_least(cap)
     }
 
-    fn notify_writable(&self, _events: Option<&mut Vec<Event>>) {
-        unimplemented!()
+    fn put(&self, buf: Vec<u8>) {
+        self.lock().unwrap().put(buf)
     }
 }
 
-struct BufferPool {
+/// A pool of the buffers used for the internal buffering of a [`NamedPipe`].
+///
+/// Each named pipe owns a pool by default, a pool can be shared between pipes
+/// using [`NamedPipeBuilder::buffer_pool`].
+pub struct BufferPool {
     pool: Vec<Vec<u8>>,
 }
 
 impl BufferPool {
-    fn get(&mut self, default_cap: usize) -> Vec<u8> {
-        self.pool
-            .pop()
-            .unwrap_or_else(|| Vec::with_capacity(default_cap))
+    /// Creates a new pool holding on to at most `cap` unused buffers.
+    pub fn new(cap: usize) -> BufferPool {
+        BufferPool {
+            pool: Vec::with_capacity(cap),
+        }
+    }
+
+    /// Returns a buffer with a capacity of at least `cap`, reusing a pooled
+    /// buffer if one is large enough.
+    fn get_at_least(&mut self, cap: usize) -> Vec<u8> {
+        match self.pool.iter().position(|buf| buf.capacity() >= cap) {
+            Some(i) => self.pool.swap_remove(i),
+            None => Vec::with_capacity(cap),
+        }
     }
 
     fn put(&mut self, mut buf: Vec<u8>) {
+        unsafe {
+            buf.set_len(0);
+        }
         if self.pool.len() < self.pool.capacity() {
-            unsafe {
-                buf.set_len(0);
-            }
             self.pool.push(buf);
+        } else if let Some(smallest) = self.pool.iter_mut().min_by_key(|buf| buf.capacity()) {
+            // The pool is full, prefer keeping larger buffers around so bulk
+            // writes don't have to reallocate.
+            if smallest.capacity() < buf.capacity() {
+                *smallest = buf;
+            }
         }
     }
 }
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("len", &self.pool.len())
+            .field("cap", &self.pool.capacity())
+            .finish()
+    }
+}
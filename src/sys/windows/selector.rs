@@ -17,6 +17,7 @@ use std::{fmt, io};
 use winapi::shared::winerror::WAIT_TIMEOUT;
 use winapi::um::minwinbase::OVERLAPPED;
 use winapi::um::minwinbase::OVERLAPPED_ENTRY;
+use winapi::um::synchapi::ResetEvent;
 
 const WAKE: Token = Token(std::usize::MAX);
 
@@ -158,7 +159,7 @@ impl Selector {
             let callback = unsafe { (*(status.overlapped() as *mut Overlapped)).callback };
 
             trace!("select; -> got overlapped");
-            callback(status.entry());
+            callback(status.entry(), Some(&mut events.events));
         }
 
         trace!("returning");
@@ -479,12 +480,12 @@ macro_rules! overlapped2arc {
 #[repr(C)]
 pub(crate) struct Overlapped {
     inner: UnsafeCell<miow::Overlapped>,
-    pub(crate) callback: fn(&OVERLAPPED_ENTRY),
+    pub(crate) callback: fn(&OVERLAPPED_ENTRY, Option<&mut Vec<Event>>),
 }
 
 #[cfg(feature = "os-ext")]
 impl Overlapped {
-    pub(crate) fn new(cb: fn(&OVERLAPPED_ENTRY)) -> Overlapped {
+    pub(crate) fn new(cb: fn(&OVERLAPPED_ENTRY, Option<&mut Vec<Event>>)) -> Overlapped {
         Overlapped {
             inner: UnsafeCell::new(miow::Overlapped::zero()),
             callback: cb,
@@ -497,6 +498,26 @@ impl Overlapped {
     pub(crate) fn as_mut_ptr(&self) -> *mut OVERLAPPED {
         unsafe { (*self.inner.get()).raw() }
     }
+
+    /// Clears the state left behind by a previous operation, keeping the
+    /// event handle but resetting it to nonsignaled.
+    ///
+    /// # Safety
+    ///
+    /// No I/O may be pending on this `OVERLAPPED`: the system writes the
+    /// result of a pending operation into it, and signals the event, when the
+    /// operation completes, which would race with the reset. So the operation
+    /// issued with it last must have completed, and its completion must have
+    /// been dequeued, before this is called.
+    pub(crate) unsafe fn reset(&self) {
+        let overlapped = &mut *self.as_mut_ptr();
+        let event = overlapped.hEvent;
+        *overlapped = std::mem::zeroed();
+        overlapped.hEvent = event;
+        if !event.is_null() {
+            ResetEvent(event);
+        }
+    }
 }
 
 impl fmt::Debug for Overlapped {
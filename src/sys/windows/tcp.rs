@@ -23,7 +23,7 @@ use crate::net::TcpKeepalive;
 use crate::sys::windows::from_raw_arc::FromRawArc;
 use crate::sys::windows::net::{init, new_socket, socket_addr};
 use crate::sys::windows::selector::{Overlapped, ReadyBinding};
-use crate::sys::windows::{Family, Ready, Registration};
+use crate::sys::windows::{Event, Family, Ready, Registration};
 use crate::{event, Interest, Registry, Token};
 use iovec::IoVec;
 use log::trace;
@@ -687,7 +687,7 @@ impl StreamImp {
     }
 }
 
-fn read_done(status: &OVERLAPPED_ENTRY) {
+fn read_done(status: &OVERLAPPED_ENTRY, _: Option<&mut Vec<Event>>) {
     let status = CompletionStatus::from_entry(status);
     let me2 = StreamImp {
         inner: unsafe { overlapped2arc!(status.overlapped(), StreamIo, read) },
@@ -724,7 +724,7 @@ fn read_done(status: &OVERLAPPED_ENTRY) {
     }
 }
 
-fn write_done(status: &OVERLAPPED_ENTRY) {
+fn write_done(status: &OVERLAPPED_ENTRY, _: Option<&mut Vec<Event>>) {
     let status = CompletionStatus::from_entry(status);
     trace!("finished a write {}", status.bytes_transferred());
     let me2 = StreamImp {
@@ -1008,7 +1008,7 @@ impl ListenerImp {
     }
 }
 
-fn accept_done(status: &OVERLAPPED_ENTRY) {
+fn accept_done(status: &OVERLAPPED_ENTRY, _: Option<&mut Vec<Event>>) {
     let status = CompletionStatus::from_entry(status);
     let me2 = ListenerImp {
         inner: unsafe { overlapped2arc!(status.overlapped(), ListenerIo, accept) },
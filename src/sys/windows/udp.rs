@@ -5,7 +5,7 @@
 
 use crate::sys::windows::from_raw_arc::FromRawArc;
 use crate::sys::windows::selector::{Overlapped, ReadyBinding};
-use crate::sys::windows::{Ready, Registration};
+use crate::sys::windows::{Event, Ready, Registration};
 use crate::{event, Interest, Registry, Token};
 use log::trace;
 use miow::iocp::CompletionStatus;
@@ -463,7 +463,7 @@ impl Drop for UdpSocket {
     }
 }
 
-fn send_done(status: &OVERLAPPED_ENTRY) {
+fn send_done(status: &OVERLAPPED_ENTRY, _: Option<&mut Vec<Event>>) {
     let status = CompletionStatus::from_entry(status);
     trace!("finished a send {}", status.bytes_transferred());
     let me2 = Imp {
@@ -474,7 +474,7 @@ fn send_done(status: &OVERLAPPED_ENTRY) {
     me2.add_readiness(&mut me, Ready::WRITABLE);
 }
 
-fn recv_done(status: &OVERLAPPED_ENTRY) {
+fn recv_done(status: &OVERLAPPED_ENTRY, _: Option<&mut Vec<Event>>) {
     let status = CompletionStatus::from_entry(status);
     trace!("finished a recv {}", status.bytes_transferred());
     let me2 = Imp {
@@ -1,15 +1,26 @@
 #![cfg(all(windows, feature = "os-poll", feature = "os-ext"))]
 
 use std::fs::OpenOptions;
-use std::io::{self, Read, Write};
+use std::io::{self, IoSlice, Read, Write};
 use std::os::windows::fs::OpenOptionsExt;
-use std::os::windows::io::{FromRawHandle, IntoRawHandle};
-use std::time::Duration;
+use std::os::windows::io::{FromRawHandle, IntoRawHandle, RawHandle};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use mio::windows::NamedPipe;
+use mio::windows::{
+    dispatch_completion, BufferPool, FrameLength, FramedPipe, NamedPipe, NamedPipeBuilder,
+    OverlappedEvents, PipeAccess, PipeEvent, Readiness, ReconnectingClient,
+};
 use mio::{Events, Interest, Poll, Token};
 use rand::Rng;
+use winapi::shared::minwindef::{FALSE, TRUE};
 use winapi::shared::winerror::*;
+use winapi::um::synchapi::CreateEventW;
 use winapi::um::winbase::FILE_FLAG_OVERLAPPED;
 
 fn _assert_kinds() {
@@ -28,9 +39,13 @@ macro_rules! t {
     };
 }
 
-fn server() -> (NamedPipe, String) {
+fn pipe_name() -> String {
     let num: u64 = rand::thread_rng().gen();
-    let name = format!(r"\\.\pipe\my-pipe-{}", num);
+    format!(r"\\.\pipe\my-pipe-{}", num)
+}
+
+fn server() -> (NamedPipe, String) {
+    let name = pipe_name();
     let pipe = t!(NamedPipe::new(&name));
     (pipe, name)
 }
@@ -251,6 +266,34 @@ fn write_then_drop() {
     assert_eq!(&buf[..4], b"1234");
 }
 
+#[test]
+fn readable_on_eof() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+    drop(client);
+
+    loop {
+        t!(poll.poll(&mut events, None));
+        if events
+            .iter()
+            .any(|e| e.token() == Token(0) && e.is_readable())
+        {
+            break;
+        }
+    }
+    let mut buf = [0; 10];
+    assert_eq!(t!(server.read(&mut buf)), 0);
+}
+
 #[test]
 fn connect_twice() {
     let (mut server, name) = server();
@@ -326,6 +369,28 @@ fn reregister_deregister_before_register() {
     );
 }
 
+#[test]
+fn deregister_cancels_read() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    // Registering scheduled a read, which is cancelled by deregistering.
+    t!(poll.registry().deregister(&mut server));
+    t!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+    assert!(!events.iter().any(|e| e.token() == Token(0)));
+
+    // No completion holds on to the pipe anymore.
+    t!(server.into_miow());
+}
+
 #[test]
 fn reregister_deregister_different_poll() {
     let (mut pipe, _) = server();
@@ -351,3 +416,1506 @@ fn reregister_deregister_different_poll() {
         io::ErrorKind::AlreadyExists,
     );
 }
+
+#[test]
+fn write_all_bufs_then_read() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll.registry().register(
+        &mut server,
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    t!(poll.registry().register(
+        &mut client,
+        Token(1),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    let bufs = [
+        IoSlice::new(b"12"),
+        IoSlice::new(b"34"),
+        IoSlice::new(b"56"),
+    ];
+    t!(client.write_all_bufs(&bufs));
+
+    loop {
+        t!(poll.poll(&mut events, None));
+        let events = events.iter().collect::<Vec<_>>();
+        if let Some(event) = events.iter().find(|e| e.token() == Token(0)) {
+            if event.is_readable() {
+                break;
+            }
+        }
+    }
+
+    let mut buf = [0; 10];
+    assert_eq!(t!(server.read(&mut buf)), 6);
+    assert_eq!(&buf[..6], b"123456");
+}
+
+#[test]
+fn read_timeout() {
+    let (mut server, mut client) = pipe();
+    assert_eq!(server.read_timeout(), None);
+    assert_eq!(
+        server
+            .set_read_timeout(Some(Duration::from_secs(0)))
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+    t!(server.set_read_timeout(Some(Duration::from_millis(10))));
+    assert_eq!(server.read_timeout(), Some(Duration::from_millis(10)));
+
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+
+    let mut events = Events::with_capacity(128);
+    loop {
+        t!(poll.poll(&mut events, None));
+        let events = events.iter().collect::<Vec<_>>();
+        if let Some(event) = events.iter().find(|e| e.token() == Token(0)) {
+            if event.is_readable() {
+                break;
+            }
+        }
+    }
+
+    let mut buf = [0; 10];
+    assert_eq!(
+        server.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::TimedOut
+    );
+
+    t!(server.set_read_timeout(None));
+    assert_eq!(server.read_timeout(), None);
+}
+
+#[test]
+fn shared_buffer_pool() {
+    let pool = Arc::new(Mutex::new(BufferPool::new(8)));
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name)
+        .buffer_pool(pool.clone())
+        .create());
+    let mut other = t!(NamedPipeBuilder::new(format!("{}-other", name))
+        .buffer_pool(pool)
+        .create());
+    let mut client = client(&name);
+
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut other, Token(1), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(2), Interest::WRITABLE));
+
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+    assert_eq!(t!(client.write(b"1234")), 4);
+
+    loop {
+        t!(poll.poll(&mut events, None));
+        let events = events.iter().collect::<Vec<_>>();
+        if let Some(event) = events.iter().find(|e| e.token() == Token(0)) {
+            if event.is_readable() {
+                break;
+            }
+        }
+    }
+
+    let mut buf = [0; 10];
+    assert_eq!(t!(server.read(&mut buf)), 4);
+    assert_eq!(&buf[..4], b"1234");
+}
+
+#[test]
+fn reregister_no_spurious_writable() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::WRITABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::READABLE));
+
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+    assert!(events
+        .iter()
+        .any(|e| e.token() == Token(0) && e.is_writable()));
+
+    for i in 2..10 {
+        t!(poll
+            .registry()
+            .reregister(&mut server, Token(i), Interest::WRITABLE));
+        t!(poll.poll(&mut events, Some(Duration::from_millis(10))));
+        assert!(!events.iter().any(|e| e.is_writable()));
+    }
+
+    // A completed write is a real transition.
+    assert_eq!(t!(server.write(b"1234")), 4);
+    let mut writable = 0;
+    while writable == 0 {
+        t!(poll.poll(&mut events, None));
+        writable += events.iter().filter(|e| e.is_writable()).count();
+    }
+    t!(poll.poll(&mut events, Some(Duration::from_millis(10))));
+    writable += events.iter().filter(|e| e.is_writable()).count();
+    assert_eq!(writable, 1);
+}
+
+#[test]
+fn register_writable_doesnt_read() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::WRITABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    // No read was scheduled, so the data stays in the pipe.
+    assert_eq!(t!(client.write(b"1234")), 4);
+    t!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+    assert!(!events
+        .iter()
+        .any(|e| e.token() == Token(0) && e.is_readable()));
+    assert_eq!(server.bytes_read(), 0);
+}
+
+#[test]
+fn reregister_toggle_readable() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    // Without readable interest the data isn't picked up.
+    t!(poll
+        .registry()
+        .reregister(&mut server, Token(0), Interest::WRITABLE));
+    assert_eq!(t!(client.write(b"1234")), 4);
+    t!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+    assert!(!events
+        .iter()
+        .any(|e| e.token() == Token(0) && e.is_readable()));
+
+    // Adding the interest back schedules a read.
+    t!(poll.registry().reregister(
+        &mut server,
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    loop {
+        t!(poll.poll(&mut events, None));
+        if events
+            .iter()
+            .any(|e| e.token() == Token(0) && e.is_readable())
+        {
+            break;
+        }
+    }
+    let mut buf = [0; 10];
+    assert_eq!(t!(server.read(&mut buf)), 4);
+    assert_eq!(&buf[..4], b"1234");
+
+    // Removing it again cancels the read scheduled after draining, without
+    // losing any data.
+    t!(poll
+        .registry()
+        .reregister(&mut server, Token(0), Interest::WRITABLE));
+    t!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+    assert_eq!(t!(client.write(b"5678")), 4);
+    t!(poll
+        .registry()
+        .reregister(&mut server, Token(0), Interest::READABLE));
+    loop {
+        t!(poll.poll(&mut events, None));
+        if events
+            .iter()
+            .any(|e| e.token() == Token(0) && e.is_readable())
+        {
+            break;
+        }
+    }
+    assert_eq!(t!(server.read(&mut buf)), 4);
+    assert_eq!(&buf[..4], b"5678");
+}
+
+#[test]
+fn access_direction() {
+    let name = pipe_name();
+    let mut inbound = t!(NamedPipeBuilder::new(&name)
+        .access(PipeAccess::Inbound)
+        .create());
+    assert_eq!(
+        inbound.write(b"1234").unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+
+    let name = format!("{}-out", name);
+    let mut outbound = t!(NamedPipeBuilder::new(&name)
+        .access(PipeAccess::Outbound)
+        .create());
+    let mut buf = [0; 10];
+    assert_eq!(
+        outbound.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn first_instance() {
+    let name = pipe_name();
+    let _first = t!(NamedPipeBuilder::new(&name).create());
+    let err = NamedPipeBuilder::new(&name).create().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    let _second = t!(NamedPipeBuilder::new(&name).first_instance(false).create());
+}
+
+#[test]
+fn anonymous_pipe_pair() {
+    let (mut server, mut client) = t!(mio::windows::anonymous_pipe());
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+    assert_eq!(t!(client.write(b"1234")), 4);
+
+    loop {
+        t!(poll.poll(&mut events, None));
+        if events
+            .iter()
+            .any(|e| e.token() == Token(0) && e.is_readable())
+        {
+            break;
+        }
+    }
+    let mut buf = [0; 10];
+    assert_eq!(t!(server.read(&mut buf)), 4);
+    assert_eq!(&buf[..4], b"1234");
+}
+
+#[test]
+fn blocking_without_poll() {
+    let (server, client) = pipe();
+    assert_eq!(t!(client.write_blocking(b"1234", None)), 4);
+    let mut buf = [0; 10];
+    assert_eq!(t!(server.read_blocking(&mut buf, None)), 4);
+    assert_eq!(&buf[..4], b"1234");
+
+    let err = server
+        .read_blocking(&mut buf, Some(Duration::from_millis(10)))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    drop(client);
+    assert_eq!(t!(server.read_blocking(&mut buf, None)), 0);
+}
+
+#[test]
+fn blocking_mode() {
+    let (mut server, mut client) = pipe();
+    t!(server.set_blocking(true));
+    t!(client.set_blocking(true));
+    assert_eq!(t!(client.write(b"1234")), 4);
+    let mut buf = [0; 10];
+    assert_eq!(t!(server.read(&mut buf)), 4);
+    assert_eq!(&buf[..4], b"1234");
+
+    // Registering requires non-blocking mode.
+    let poll = t!(Poll::new());
+    let err = poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert!(!server.is_registered());
+    t!(server.set_blocking(false));
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    assert!(server.set_blocking(true).is_err());
+}
+
+#[test]
+fn write_reports_buffered_len() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    // Far more than fits in the system's buffer, so the write stays in flight
+    // but is still reported as written in full.
+    let data = vec![0; 1 << 20];
+    assert_eq!(t!(client.write(&data)), data.len());
+    assert_eq!(client.writable_after(), Some(data.len()));
+    assert_eq!(
+        client.write(b"1").unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    assert_eq!(
+        client.try_write(b"1").unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn message_larger_than_read() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name)
+        .message_mode(true)
+        .max_read_ahead(4)
+        .create());
+    let mut client = client(&name);
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    // The message doesn't fit a single internal read, which completes with
+    // `ERROR_MORE_DATA`. No data may be lost.
+    assert_eq!(t!(client.write(b"12345678")), 8);
+    let mut received = Vec::new();
+    while received.len() < 8 {
+        t!(poll.poll(&mut events, None));
+        let mut buf = [0; 10];
+        loop {
+            match server.read(&mut buf) {
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+    }
+    assert_eq!(received, b"12345678");
+}
+
+#[test]
+fn concurrent_readers() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    let data = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+    assert_eq!(t!(client.write(&data)), data.len());
+
+    let server = Arc::new(server);
+    let received = Arc::new(AtomicUsize::new(0));
+    let readers = (0..2)
+        .map(|_| {
+            let server = server.clone();
+            let received = received.clone();
+            thread::spawn(move || {
+                let mut got = Vec::new();
+                while received.load(SeqCst) < 200 {
+                    let mut buf = [0; 7];
+                    match (&*server).read(&mut buf) {
+                        Ok(n) => {
+                            got.extend_from_slice(&buf[..n]);
+                            received.fetch_add(n, SeqCst);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+                got
+            })
+        })
+        .collect::<Vec<_>>();
+
+    while received.load(SeqCst) < 200 {
+        t!(poll.poll(&mut events, Some(Duration::from_millis(10))));
+    }
+
+    let mut all = readers
+        .into_iter()
+        .flat_map(|reader| reader.join().unwrap())
+        .collect::<Vec<_>>();
+    all.sort();
+    assert_eq!(all, data);
+}
+
+#[test]
+fn message_too_large() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name)
+        .message_mode(true)
+        .max_message_size(4)
+        .create());
+    let _client = client(&name);
+
+    let err = server.write(b"12345").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let err = server.write_blocking(b"12345", None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(t!(server.write_blocking(b"1234", None)), 4);
+}
+
+#[test]
+fn read_messages() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name)
+        .message_mode(true)
+        .max_read_ahead(4)
+        .create());
+    let client = client(&name);
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+
+    assert_eq!(t!(client.write_blocking(b"123456", None)), 6);
+    assert_eq!(t!(client.write_blocking(b"ab", None)), 2);
+    drop(client);
+
+    let mut messages = server.messages();
+    let mut received = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        match messages.next_message(&mut buf) {
+            Ok(Some(n)) => {
+                assert_eq!(n, buf.len());
+                received.push(buf.clone());
+            }
+            Ok(None) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+            }
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    assert_eq!(received, vec![b"123456".to_vec(), b"ab".to_vec()]);
+}
+
+#[test]
+fn refresh_read() {
+    let (mut server, client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, Some(Duration::from_millis(10))));
+
+    // The cancelled read is silently replaced by a new one.
+    t!(server.refresh_read());
+    t!(poll.poll(&mut events, Some(Duration::from_millis(10))));
+    assert!(events.iter().all(|e| !e.is_readable()));
+
+    assert_eq!(t!(client.write_blocking(b"1234", None)), 4);
+    let mut buf = [0; 10];
+    loop {
+        t!(poll.poll(&mut events, None));
+        match server.read(&mut buf) {
+            Ok(n) => {
+                assert_eq!(&buf[..n], b"1234");
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+}
+
+#[test]
+fn strict_eof() {
+    let name = pipe_name();
+    let server = t!(NamedPipeBuilder::new(&name).strict_eof(true).create());
+    drop(client(&name));
+
+    let mut buf = [0; 10];
+    let err = server.read_blocking(&mut buf, None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn read_exact_ready() {
+    let (mut server, client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+
+    assert_eq!(t!(client.write_blocking(b"123", None)), 3);
+    let mut frame = [0; 6];
+    let mut filled = 0;
+    while filled < 3 {
+        t!(poll.poll(&mut events, None));
+        match server.read_exact_ready(&mut frame[filled..]) {
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    assert_eq!(filled, 3);
+    let err = server.read_exact_ready(&mut frame[filled..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    assert_eq!(t!(client.write_blocking(b"456", None)), 3);
+    drop(client);
+    while filled < 6 {
+        t!(poll.poll(&mut events, None));
+        match server.read_exact_ready(&mut frame[filled..]) {
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    assert_eq!(&frame, b"123456");
+}
+
+#[test]
+fn take_read_buffer() {
+    let (mut server, client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+
+    assert_eq!(t!(client.write_blocking(b"1234", None)), 4);
+    drop(client);
+    let mut received = Vec::new();
+    loop {
+        match server.take_read_buffer() {
+            Ok(Some(buf)) => received.extend_from_slice(&buf),
+            Ok(None) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+            }
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    assert_eq!(received, b"1234");
+}
+
+#[test]
+fn framed_pipe() {
+    let (server, client) = pipe();
+    let mut server = FramedPipe::new(server, FrameLength::U16Be);
+    let mut client = FramedPipe::new(client, FrameLength::U16Be);
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+    t!(poll.poll(&mut events, None));
+
+    t!(client.send_frame(b"hello"));
+    t!(client.send_frame(b""));
+    let mut frames = Vec::new();
+    while frames.len() < 2 {
+        match server.next_frame() {
+            Ok(Some(frame)) => frames.push(frame),
+            Ok(None) => panic!("unexpected end of stream"),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+                let _ = client.flush();
+            }
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    assert_eq!(frames, vec![b"hello".to_vec(), Vec::new()]);
+
+    let err = client.send_frame(&[0; 70_000]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn recycle_across_clients() {
+    let (mut server, name) = server();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+
+    // No connect was issued yet.
+    assert!(!t!(server.poll_connected()));
+    let mut client = Some(client(&name));
+    t!(server.connect());
+    assert!(t!(server.poll_connected()));
+    for _ in 0..3 {
+        drop(client.take());
+        assert_eq!(
+            server.recycle().err().unwrap().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // No client yet, so the connect must not complete.
+        t!(poll.poll(&mut events, Some(Duration::from_millis(10))));
+        assert!(!t!(server.poll_connected()));
+
+        client = Some(self::client(&name));
+        while !t!(server.poll_connected()) {
+            t!(poll.poll(&mut events, None));
+        }
+    }
+}
+
+#[test]
+fn last_os_error_code() {
+    let (mut server, client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+    assert_eq!(server.last_os_error_code(), None);
+
+    drop(client);
+    let mut buf = [0; 10];
+    loop {
+        t!(poll.poll(&mut events, None));
+        match server.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => panic!("read {} bytes", n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    // The broken pipe is reported as EOF, but its code is still available.
+    assert_eq!(server.last_os_error_code(), Some(ERROR_BROKEN_PIPE as i32));
+}
+
+#[test]
+fn register_batch() {
+    let (mut a, name) = server();
+    let (mut b, _) = server();
+    let (mut c, _) = server();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut c, Token(2), Interest::READABLE));
+
+    // `c` is already registered, so neither `a` nor `b` may be.
+    let err = NamedPipe::register_batch(
+        poll.registry(),
+        &mut [
+            (&mut a, Token(0), Interest::READABLE),
+            (&mut c, Token(2), Interest::READABLE),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert!(!a.is_registered());
+
+    t!(NamedPipe::register_batch(
+        poll.registry(),
+        &mut [
+            (&mut a, Token(0), Interest::READABLE),
+            (&mut b, Token(1), Interest::WRITABLE),
+        ],
+    ));
+    assert_eq!(a.registered_token(), Some(Token(0)));
+    assert_eq!(b.registered_token(), Some(Token(1)));
+
+    // The pipes are associated with the `Poll` and report their events.
+    let client = client(&name);
+    assert_eq!(t!(client.write_blocking(b"1", None)), 1);
+    let mut events = Events::with_capacity(128);
+    'outer: loop {
+        t!(poll.poll(&mut events, None));
+        for event in &events {
+            if event.is_readable() && event.token() == Token(0) {
+                break 'outer;
+            }
+        }
+    }
+    let mut buf = [0; 1];
+    assert_eq!(t!(a.read(&mut buf)), 1);
+}
+
+#[test]
+fn new_registered() {
+    let name = pipe_name();
+    let mut poll = t!(Poll::new());
+    let server = t!(NamedPipe::new_registered(
+        &name,
+        poll.registry(),
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    assert_eq!(server.registered_token(), Some(Token(0)));
+
+    // The pipe reports its events to the `Poll` it was registered with.
+    let _client = client(&name);
+    t!(server.connect());
+    let mut events = Events::with_capacity(128);
+    'outer: loop {
+        t!(poll.poll(&mut events, None));
+        for event in &events {
+            if event.is_writable() && event.token() == Token(0) {
+                break 'outer;
+            }
+        }
+    }
+}
+
+#[test]
+fn reject_remote_clients() {
+    let name = pipe_name();
+    let server = t!(NamedPipeBuilder::new(&name)
+        .reject_remote_clients(true)
+        .create());
+    let client = client(&name);
+
+    assert_eq!(t!(client.write_blocking(b"local", None)), 5);
+    let mut buf = [0; 5];
+    assert_eq!(t!(server.read_blocking(&mut buf, None)), 5);
+    assert_eq!(&buf, b"local");
+}
+
+#[test]
+fn buffer_alignment() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name).buffer_alignment(512).create());
+    let mut client = client(&name);
+    let mut poll = t!(Poll::new());
+    t!(poll.registry().register(
+        &mut server,
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    let mut events = Events::with_capacity(128);
+
+    assert_eq!(t!(client.write_blocking(b"aligned", None)), 7);
+    let mut buf = [0; 10];
+    loop {
+        t!(poll.poll(&mut events, None));
+        match server.read(&mut buf) {
+            Ok(n) => {
+                assert_eq!(&buf[..n], b"aligned");
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+
+    assert_eq!(t!(server.write(b"back")), 4);
+    assert_eq!(t!(client.read_blocking(&mut buf, None)), 4);
+    assert_eq!(&buf[..4], b"back");
+}
+
+#[test]
+fn lifecycle_hook() {
+    let (mut server, name) = server();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen2 = seen.clone();
+    server.set_lifecycle_hook(Some(Box::new(move |event| {
+        seen2.lock().unwrap().push(event)
+    })));
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+
+    let client = client(&name);
+    t!(server.connect());
+    drop(client);
+    let mut buf = [0; 10];
+    loop {
+        t!(poll.poll(&mut events, None));
+        match server.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => panic!("read {} bytes", n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    t!(server.disconnect());
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            PipeEvent::Connected,
+            PipeEvent::Eof,
+            PipeEvent::Disconnected
+        ]
+    );
+}
+
+#[test]
+fn reconnecting_client() {
+    let (server, name) = server();
+    let mut client = t!(ReconnectingClient::connect(&name));
+    let mut poll = t!(Poll::new());
+    t!(poll.registry().register(
+        &mut client,
+        Token(1),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    let mut events = Events::with_capacity(128);
+
+    // Restart the server.
+    drop(server);
+    let server = t!(NamedPipe::new(&name));
+
+    let mut buf = [0; 10];
+    loop {
+        t!(poll.poll(&mut events, None));
+        match client.read(&mut buf) {
+            Ok(n) => panic!("read {} bytes", n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => break,
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    assert_eq!(client.reconnects(), 1);
+    assert_eq!(client.get_ref().registered_token(), Some(Token(1)));
+
+    assert_eq!(t!(client.get_ref().write_blocking(b"hello", None)), 5);
+    assert_eq!(t!(server.read_blocking(&mut buf, None)), 5);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn last_write_was_synchronous() {
+    let (mut server, _client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::WRITABLE));
+    assert!(!server.last_write_was_synchronous());
+
+    // The pipe's buffer has plenty of room for a small write.
+    assert_eq!(t!(server.write(b"1234")), 4);
+    assert!(server.last_write_was_synchronous());
+}
+
+#[test]
+fn normalize_addr() {
+    let name = pipe_name();
+    let server = t!(NamedPipe::new(&name[r"\\.\pipe\".len()..]));
+    assert_eq!(server.addr(), Some(name.as_ref()));
+    drop(client(&name));
+
+    let err = NamedPipe::new(r"\pipe\my-pipe").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let err = NamedPipe::new("").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn readiness() {
+    let (mut server, client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll.registry().register(
+        &mut server,
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    let mut events = Events::with_capacity(128);
+
+    let readiness = server.readiness();
+    assert!(readiness.is_writable() && readiness.is_connected());
+    assert!(!readiness.is_readable());
+
+    assert_eq!(t!(client.write_blocking(b"data", None)), 4);
+    while !server.readiness().is_readable() {
+        t!(poll.poll(&mut events, None));
+    }
+    assert!(server
+        .readiness()
+        .contains(Readiness::READABLE | Readiness::WRITABLE));
+}
+
+#[test]
+fn associate_with_iocp() {
+    use std::ptr;
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::ioapiset::{CreateIoCompletionPort, GetQueuedCompletionStatus};
+    use winapi::um::winbase::INFINITE;
+
+    let (server, client) = pipe();
+    let port = unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, ptr::null_mut(), 0, 1) };
+    assert!(!port.is_null());
+    t!(server.associate_with_iocp(port as _, 7));
+    let err = server.associate_with_iocp(port as _, 7).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+    // Runs one iteration of the caller's completion port loop.
+    let dispatch = || {
+        let (mut bytes, mut key, mut overlapped) = (0, 0, ptr::null_mut());
+        let ok = unsafe {
+            GetQueuedCompletionStatus(port, &mut bytes, &mut key, &mut overlapped, INFINITE)
+        };
+        let err = if ok == 0 {
+            Some(io::Error::last_os_error())
+        } else {
+            None
+        };
+        assert_eq!(key, 7);
+        unsafe { dispatch_completion(overlapped, bytes as usize, err) };
+    };
+
+    assert_eq!(t!(client.write_blocking(b"port", None)), 4);
+    dispatch();
+    let mut buf = [0; 4];
+    assert_eq!(t!((&server).read(&mut buf)), 4);
+    assert_eq!(&buf, b"port");
+
+    // Complete the next read before closing the port.
+    drop(client);
+    dispatch();
+    drop(server);
+    unsafe { CloseHandle(port) };
+}
+
+#[test]
+fn peek_available() {
+    let (server, client) = pipe();
+    assert_eq!(t!(server.peek_available()), 0);
+
+    // No read is in flight as the server isn't registered.
+    assert_eq!(t!(client.write_blocking(b"12345", None)), 5);
+    assert_eq!(t!(server.peek_available()), 5);
+    let mut buf = [0; 5];
+    assert_eq!(t!(server.read_blocking(&mut buf, None)), 5);
+    assert_eq!(t!(server.peek_available()), 0);
+}
+
+#[test]
+fn connect_with_deadline() {
+    let (mut server, _) = server();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let err = server.connect_with_deadline(deadline).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    // No client connects, the cancelled connect wakes up the poll.
+    let err = loop {
+        t!(poll.poll(&mut events, None));
+        match server.poll_connected() {
+            Ok(false) => {}
+            Ok(true) => panic!("connected without a client"),
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(Instant::now() >= deadline);
+}
+
+#[test]
+fn connect_retries() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name).connect_retries(5).create());
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+
+    // The client leaving before the connect makes it fail with
+    // `ERROR_NO_DATA`, the retry doesn't block the caller.
+    drop(client(&name));
+    let err = server.connect().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    // The pipe is busy until the retry is issued, which then picks up the
+    // next client.
+    let _client = loop {
+        let res = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(&name);
+        match res {
+            Ok(file) => break file,
+            Err(ref e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => thread::yield_now(),
+            Err(e) => panic!("{}", e),
+        }
+    };
+    while !t!(server.poll_connected()) {
+        t!(poll.poll(&mut events, None));
+    }
+}
+
+#[test]
+fn downgrade() {
+    let (server, _) = server();
+    let weak = server.downgrade();
+
+    let upgraded = weak.upgrade().unwrap();
+    assert!(upgraded == server);
+    drop(server);
+    assert!(weak.upgrade().is_some());
+    drop(upgraded);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn read_timeout_once() {
+    let (mut server, mut client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut client, Token(1), Interest::WRITABLE));
+    let mut events = Events::with_capacity(128);
+
+    let mut buf = [0; 16];
+    let err = server
+        .read_timeout_once(&mut buf, Duration::from_millis(20))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    let server = Arc::new(server);
+    let done = Arc::new(AtomicUsize::new(0));
+    let reader = {
+        let server = server.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 16];
+            let res = server.read_timeout_once(&mut buf, Duration::from_secs(10));
+            done.store(1, SeqCst);
+            res.map(|n| buf[..n].to_vec())
+        })
+    };
+
+    // The read timed out above is still in flight and receives this.
+    assert_eq!(t!(client.write(b"hello")), 5);
+    while done.load(SeqCst) == 0 {
+        t!(poll.poll(&mut events, Some(Duration::from_millis(10))));
+    }
+    assert_eq!(t!(reader.join().unwrap()), b"hello");
+}
+
+#[test]
+fn nowait_at_open() {
+    let name = pipe_name();
+    let nowait = t!(NamedPipeBuilder::new(&name).nowait_at_open(true).create());
+    assert!(t!(nowait.handle_state()).nowait);
+
+    let (wait, _) = server();
+    assert!(!t!(wait.handle_state()).nowait);
+}
+
+#[test]
+fn accept_and_read() {
+    let (server, name) = server();
+    t!(server.set_blocking(true));
+    let mut buf = [0; 16];
+    let err = server
+        .accept_and_read(&mut buf, Some(Duration::from_millis(20)))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    // Connect errors wrap the original error.
+    assert!(err.get_ref().unwrap().is::<io::Error>());
+
+    let client = thread::spawn(move || {
+        let client = client(&name);
+        assert_eq!(t!(client.write_blocking(b"request", None)), 7);
+        client
+    });
+    let n = t!(server.accept_and_read(&mut buf, Some(Duration::from_secs(10))));
+    assert_eq!(&buf[..n], b"request");
+    drop(client.join().unwrap());
+}
+
+#[test]
+fn sub_tokens() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name).sub_tokens(true).create());
+    let mut poll = t!(Poll::new());
+    t!(poll.registry().register(
+        &mut server,
+        Token(10),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    let mut events = Events::with_capacity(128);
+    let mut wait_for = |token: Token| loop {
+        t!(poll.poll(&mut events, None));
+        if events.iter().any(|e| e.token() == token) {
+            break;
+        }
+    };
+
+    // Connects are reported with the base token.
+    let client = client(&name);
+    t!(server.connect());
+    wait_for(Token(10));
+
+    // Reads with the base plus one.
+    assert_eq!(t!(client.write_blocking(b"1234", None)), 4);
+    wait_for(Token(11));
+    let mut buf = [0; 4];
+    assert_eq!(t!(server.read(&mut buf)), 4);
+
+    // Writes with the base plus two.
+    assert_eq!(t!(server.write(b"1234")), 4);
+    wait_for(Token(12));
+}
+
+#[test]
+fn max_events_per_poll() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name).max_events_per_poll(1).create());
+    let client = client(&name);
+    let mut poll = t!(Poll::new());
+    t!(poll.registry().register(
+        &mut server,
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    let mut events = Events::with_capacity(128);
+    loop {
+        t!(poll.poll(&mut events, None));
+        if events
+            .iter()
+            .any(|e| e.token() == Token(0) && e.is_writable())
+        {
+            break;
+        }
+    }
+
+    // Both the write and the read complete before the next poll, but only
+    // one of their events is delivered per poll.
+    assert_eq!(t!(server.write(b"1")), 1);
+    assert_eq!(t!(client.write_blocking(b"2", None)), 1);
+    let (mut readable, mut writable) = (false, false);
+    let mut polls = 0;
+    while !(readable && writable) {
+        t!(poll.poll(&mut events, None));
+        let ours = events
+            .iter()
+            .filter(|e| e.token() == Token(0))
+            .collect::<Vec<_>>();
+        assert!(ours.len() <= 1);
+        readable |= ours.iter().any(|e| e.is_readable());
+        writable |= ours.iter().any(|e| e.is_writable());
+        polls += 1;
+    }
+    assert!(polls >= 2);
+
+    let mut buf = [0; 1];
+    assert_eq!(t!(server.read(&mut buf)), 1);
+    assert_eq!(&buf, b"2");
+}
+
+/// Returns events with a manual-reset event for the internal read.
+fn read_event() -> OverlappedEvents {
+    let event = unsafe { CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+    assert!(!event.is_null());
+    let mut events = OverlappedEvents::default();
+    unsafe { events.set_read(event as RawHandle) };
+    events
+}
+
+#[test]
+fn wait_any() {
+    let (mut idle, _idle_client) = pipe();
+    let (mut busy, busy_client) = pipe();
+
+    // Without events there's nothing to wait on.
+    let err = mio::windows::wait_any(&[&idle], None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    t!(idle.set_overlapped_events(read_event()));
+    t!(busy.set_overlapped_events(read_event()));
+    let poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut idle, Token(0), Interest::READABLE));
+    t!(poll
+        .registry()
+        .register(&mut busy, Token(1), Interest::READABLE));
+
+    let timeout = Some(Duration::from_millis(10));
+    let err = mio::windows::wait_any(&[&idle, &busy], timeout).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    // The read scheduled by registering completes.
+    assert_eq!(t!(busy_client.write_blocking(b"1", None)), 1);
+    let timeout = Some(Duration::from_secs(10));
+    assert_eq!(t!(mio::windows::wait_any(&[&idle, &busy], timeout)), 1);
+}
+
+#[test]
+fn read_overlapped_into() {
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name).initial_read(false).create());
+    let client = client(&name);
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+
+    assert_eq!(t!(client.write_blocking(b"1234", None)), 4);
+    let mut buf = [0; 16];
+    t!(unsafe { server.read_overlapped_into(Pin::new(&mut buf[..])) });
+    let err = server.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    loop {
+        t!(poll.poll(&mut events, None));
+        if events
+            .iter()
+            .any(|e| e.token() == Token(0) && e.is_readable())
+        {
+            break;
+        }
+    }
+    assert_eq!(t!(server.take_overlapped_read()), 4);
+    assert_eq!(&buf[..4], b"1234");
+
+    // Buffered reads resume once started.
+    server.start_reading();
+    assert_eq!(t!(client.write_blocking(b"5678", None)), 4);
+    let mut buf = [0; 16];
+    loop {
+        match server.read(&mut buf) {
+            Ok(n) => {
+                assert_eq!(&buf[..n], b"5678");
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+            }
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+}
+
+#[test]
+fn drop_with_overlapped_read() {
+    let pool = Arc::new(Mutex::new(BufferPool::new(2)));
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name)
+        .initial_read(false)
+        .buffer_pool(pool.clone())
+        .create());
+    let _client = client(&name);
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+
+    let mut buf = [0; 16];
+    t!(unsafe { server.read_overlapped_into(Pin::new(&mut buf[..])) });
+    drop(server);
+
+    // The cancelled read keeps the pipe alive until `Poll` processed it.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Arc::strong_count(&pool) > 1 {
+        assert!(Instant::now() < deadline, "cancelled read wasn't processed");
+        t!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+    }
+}
+
+#[test]
+fn into_split() {
+    let pool = Arc::new(Mutex::new(BufferPool::new(2)));
+    let name = pipe_name();
+    let mut server = t!(NamedPipeBuilder::new(&name)
+        .buffer_pool(pool.clone())
+        .create());
+    let client = client(&name);
+    let mut poll = t!(Poll::new());
+    t!(poll.registry().register(
+        &mut server,
+        Token(0),
+        Interest::READABLE | Interest::WRITABLE,
+    ));
+    let mut events = Events::with_capacity(128);
+
+    let (mut read, mut write) = server.into_split();
+    assert_eq!(t!(write.write(b"1234")), 4);
+    let mut buf = [0; 4];
+    assert_eq!(t!(client.read_blocking(&mut buf, None)), 4);
+    assert_eq!(&buf, b"1234");
+    assert_eq!(t!(client.write_blocking(b"5678", None)), 4);
+    loop {
+        match read.read(&mut buf) {
+            Ok(n) => {
+                assert_eq!(&buf[..n], b"5678");
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+            }
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+
+    let server = match NamedPipe::unsplit(read, write) {
+        Ok(server) => server,
+        Err(_) => panic!("unsplit rejected matching halves"),
+    };
+    let (other, _) = pipe();
+    let (other_read, other_write) = other.into_split();
+    let (read, write) = server.into_split();
+    let (read, other_write) = match NamedPipe::unsplit(read, other_write) {
+        Ok(_) => panic!("unsplit accepted mismatched halves"),
+        Err(halves) => halves,
+    };
+    drop((other_read, other_write));
+
+    // Dropping the last half cancels the pending read, which would otherwise
+    // keep the pipe alive for as long as the client is connected.
+    drop(write);
+    drop(read);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Arc::strong_count(&pool) > 1 {
+        assert!(Instant::now() < deadline, "pending read wasn't cancelled");
+        t!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+    }
+}
+
+#[test]
+fn max_frame_size() {
+    let (server, client) = pipe();
+    let mut server = FramedPipe::new(server, FrameLength::U32Be);
+    server.set_max_frame_size(1024);
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+
+    // Announce a frame of 1 GiB.
+    let prefix = (1u32 << 30).to_be_bytes();
+    assert_eq!(t!(client.write_blocking(&prefix, None)), 4);
+    loop {
+        match server.next_frame() {
+            Ok(frame) => panic!("unexpected frame {:?}", frame),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+            }
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn timer_affinity_failure() {
+    // No processor is in an empty mask, so applying it fails.
+    let name = pipe_name();
+    let server = t!(NamedPipeBuilder::new(&name).timer_affinity(0).create());
+    assert!(server
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .is_err());
+    assert_eq!(server.read_timeout(), None);
+    assert!(server
+        .set_write_timeout(Some(Duration::from_millis(100)))
+        .is_err());
+    assert_eq!(server.write_timeout(), None);
+}
+
+#[test]
+fn downgrade_split() {
+    let (mut server, client) = pipe();
+    let mut poll = t!(Poll::new());
+    t!(poll
+        .registry()
+        .register(&mut server, Token(0), Interest::READABLE));
+    let mut events = Events::with_capacity(128);
+    let mut buf = [0; 4];
+
+    // The halves keep the read going once the upgraded pipe is dropped.
+    let weak = server.downgrade();
+    let upgraded = weak.upgrade().unwrap();
+    let (mut read, write) = server.into_split();
+    drop(upgraded);
+    assert_eq!(t!(client.write_blocking(b"1234", None)), 4);
+    loop {
+        match read.read(&mut buf) {
+            Ok(n) => {
+                assert_eq!(&buf[..n], b"1234");
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+            }
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+
+    // And the upgraded pipe keeps it going once the halves are dropped.
+    let mut upgraded = weak.upgrade().unwrap();
+    drop(read);
+    drop(write);
+    assert_eq!(t!(client.write_blocking(b"5678", None)), 4);
+    loop {
+        match upgraded.read(&mut buf) {
+            Ok(n) => {
+                assert_eq!(&buf[..n], b"5678");
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                t!(poll.poll(&mut events, None));
+            }
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+
+    drop(upgraded);
+    assert!(weak.upgrade().is_none());
+}